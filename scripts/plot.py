@@ -22,27 +22,29 @@ else:
     view_baseline = int(sys.argv[2])
 print(f"Viewing plot for baseline {view_baseline}")
 
-# filename example is 1319371344_fringes_128chans_128T_ch169.dat
-if filename[21].isdigit():
-    fine_chans = filename[19:22]
-else:
-    if filename[20].isdigit():
-        fine_chans = filename[19:21]
-    else:
-        fine_chans = filename[19:20]
-
-fine_chans = int(fine_chans)
-print(f"Fine chans = {fine_chans}")
+# Files with a header (--header) describe themselves, legacy files (the default) have to be described by the filename
+with open(full_filename, "rb") as file:
+    header = file.read(64)
 
-if filename[30].isdigit():
-    tiles = filename[28:31]
+if header[0:8] == b"MWAXSTAT":
+    (_, _, header_size, _, _, _, _, tiles, fine_chans, _, _, floats_per_record, _) = struct.unpack(
+        "<8sIIQQIIIIIIII", header
+    )
 else:
-    if filename[29].isdigit():
-        tiles = filename[28:30]
+    header_size = 0
+
+    # filename examples are 1319371344_fringes_128chans_128T_ch169.dat and 1319371344_fringes_v2_128chans_128T_ch169.dat
+    fields = os.path.splitext(filename)[0].split("_")
+    if fields[2] in ("v2", "v3"):
+        version = fields.pop(2)
     else:
-        tiles = filename[28:29]
+        version = "v1"
+    floats_per_record = {"v1": 3, "v2": 6, "v3": 10}[version]
 
-tiles = int(tiles)
+    fine_chans = int(fields[2].removesuffix("chans"))
+    tiles = int(fields[3].removesuffix("T"))
+
+print(f"Fine chans = {fine_chans}")
 print(f"Tiles = {tiles}")
 baselines = int((tiles * (tiles + 1)) / 2)
 print(f"Baselines = {baselines}")
@@ -53,17 +55,20 @@ freqs = np.zeros(fine_chans, dtype=float)
 with open(full_filename, "rb") as file:
     bl = 0
     fc = 0 
-    bytearray = file.read(12)
+    record_format = "<" + "f" * floats_per_record
+    record_size = 4 * floats_per_record
+    file.seek(header_size)
+    bytearray = file.read(record_size)
 
     while bytearray:        
-        np_data[bl, fc, 0] = struct.unpack('fff', bytearray)[1]
-        np_data[bl, fc, 1] = struct.unpack('fff', bytearray)[2]
+        np_data[bl, fc, 0] = struct.unpack(record_format, bytearray)[1]
+        np_data[bl, fc, 1] = struct.unpack(record_format, bytearray)[2]
 
         if bl == 0:
-            freqs[fc] = struct.unpack('fff', bytearray)[0]
+            freqs[fc] = struct.unpack(record_format, bytearray)[0]
         
-        # read next triplet
-        bytearray = file.read(12)
+        # read next record
+        bytearray = file.read(record_size)
         fc = fc + 1
 
         if fc == fine_chans:
@@ -1,129 +1,1124 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
-use crate::processing;
+use crate::{
+    errors::MwaxStatsError,
+    header::{FileHeader, ObsInfo, OutputFormat, RecordLayout},
+    processing::{self, Corrections},
+};
+use birli::marlu::Complex;
+use core::ops::Range;
 use file_utils::write::Write;
-use log::{info, trace};
-use mwalib::CorrelatorContext;
+use log::{debug, info, trace, warn};
+use mwalib::{Baseline, CorrelatorContext};
+use ndarray::{s, Array2, Array3, ArrayView2, ArrayView3, Axis};
 use std::fs::File;
-use std::path::Path;
+use std::io::{self, BufWriter};
+use std::path::{Path, PathBuf};
 
-/// Outputs one binary file for an observation.
+/// Index of XX in the last axis of the auto power arrays
+const XX: usize = 0;
+/// Index of YY in the last axis of the auto power arrays
+const YY: usize = 1;
+/// Index of XY in the last axis of the auto cross pol arrays
+const XY: usize = 0;
+/// Index of YX in the last axis of the auto cross pol arrays
+const YX: usize = 1;
+/// The number of floats of each fine channel in the raw data: xx_r, xx_i, xy_r, xy_i, yx_r, yx_i, yy_r, yy_i
+const FLOATS_PER_FINE_CHAN: usize = 8;
+
+/// The layout of one timestep of the raw data of a coarse channel, as read by `processing::get_data`:
+/// [baseline][fine chan][pol][r/i], with the baselines in the order of `baselines` and the pols XX, XY, YX, YY
+#[derive(Clone, Copy, Debug)]
+pub struct RawDataLayout<'a> {
+    pub baselines: &'a [Baseline],
+    pub num_ants: usize,
+    pub num_fine_chans: usize,
+}
+
+impl<'a> RawDataLayout<'a> {
+    pub fn from_context(context: &'a CorrelatorContext) -> Self {
+        RawDataLayout {
+            baselines: &context.metafits_context.baselines,
+            num_ants: context.metafits_context.num_ants,
+            num_fine_chans: context.metafits_context.num_corr_fine_chans_per_coarse,
+        }
+    }
+}
+
+/// The auto-correlations of every antenna for one coarse channel, each indexed [ant][fine chan].
 ///
-/// Each file is named OBSID_autos_FINECHANSchans_128T.dat  (128 is the number of tiles which may vary)
+/// The power is from the last of `timestep_indices`, or the mean (of the linear power) over all of them if averaging.
+#[derive(Clone, Debug)]
+pub struct AutoSpectra {
+    pub coarse_chan_index: usize,
+    /// The timesteps the spectra, stats and cross pols are made from
+    pub timestep_indices: Vec<usize>,
+    /// Van Vleck is the only correction of the autos
+    pub corrections: Corrections,
+    pub freqs_mhz: Vec<f32>,
+    pub xx_db: Array2<f32>,
+    pub yy_db: Array2<f32>,
+    /// Only when averaging
+    pub stats: Option<AutoStats>,
+    pub cross_pols: Option<AutoCrossPols>,
+    pub waterfall: Option<AutoWaterfall>,
+}
+
+/// The spread of the power (dB) of each [ant][fine chan] over the timesteps
+#[derive(Clone, Debug)]
+pub struct AutoStats {
+    pub xx_min_db: Array2<f32>,
+    pub xx_max_db: Array2<f32>,
+    pub xx_stddev_db: Array2<f32>,
+    pub yy_min_db: Array2<f32>,
+    pub yy_max_db: Array2<f32>,
+    pub yy_stddev_db: Array2<f32>,
+}
+
+/// The mean of the complex XY and YX auto-correlations of each [ant][fine chan]
+#[derive(Clone, Debug)]
+pub struct AutoCrossPols {
+    pub xy_db: Array2<f32>,
+    pub xy_phase_deg: Array2<f32>,
+    pub yx_db: Array2<f32>,
+    pub yx_phase_deg: Array2<f32>,
+}
+
+/// The power (dB) of each [ant][timestep][fine chan]
+#[derive(Clone, Debug)]
+pub struct AutoWaterfall {
+    /// Every timestep, even when not averaging
+    pub timestep_indices: Vec<usize>,
+    pub xx_db: Array3<f32>,
+    pub yy_db: Array3<f32>,
+}
+
+/// Somewhere to put the `AutoSpectra` of each coarse channel, so `output_autocorrelations` does not need to know the
+/// output format.
+pub trait AutosWriter {
+    fn write_autos(&mut self, spectra: &AutoSpectra) -> Result<(), MwaxStatsError>;
+}
+
+/// Works out the autos of the coarse channels in `coarse_chan_range` which we have data for. We use the last timestep
+/// of `timestep_range` which each coarse channel has data for (or all of them if `average` or `waterfall` is true).
 ///
-/// File format 3 floats * num fine channels per coarse * coarse channels passed in * tiles:
-/// Slowest moving -> fastest moving
-/// [ant][fine chan freq][XX][YY]
+/// If `output_stats` is true the min, max and standard deviation are also worked out (only when averaging). If
+/// `cross_pols` or `waterfall` is true the XY and YX, or the power of every timestep, are too.
 ///
-///     fine chan freq (MHz)
-///     XX pow (dB)
-///     YY pow (dB)
+/// If `van_vleck` is true the data is read through birli with the Van Vleck correction applied (to every tile which
+/// is not flagged in the metafits), otherwise the raw data is used.
+#[allow(clippy::too_many_arguments)]
+pub fn get_autocorrelations(
+    context: &CorrelatorContext,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    average: bool,
+    output_stats: bool,
+    waterfall: bool,
+    cross_pols: bool,
+    van_vleck: bool,
+) -> Result<Vec<AutoSpectra>, MwaxStatsError> {
+    // For autos we only want the last timestep with data for each coarse channel (or all of them if averaging or
+    // producing a waterfall), but all of the coarse channels. The range may span timesteps which were not provided
+    let last_timestep_only = !(average || waterfall);
+    let timestep_indices: Vec<usize> = timestep_range
+        .clone()
+        .filter(|t| context.provided_timestep_indices.contains(t))
+        .collect();
+
+    let mut autos = Vec::new();
+
+    // One set of autos per coarse channel
+    for coarse_chan_index in coarse_chan_range.clone() {
+        // The range may span coarse channels we were not given files for
+        if !context
+            .provided_coarse_chan_indices
+            .contains(&coarse_chan_index)
+        {
+            debug!(
+                "Skipping coarse channel index: {} as no data was provided for it",
+                coarse_chan_index
+            );
+            continue;
+        }
+
+        // Not every coarse channel has data for every timestep (unless they are all common)
+        let coarse_chan_timestep_indices = select_timesteps(
+            &timestep_indices,
+            |t| processing::has_data(context, t, coarse_chan_index),
+            last_timestep_only,
+        );
+
+        if coarse_chan_timestep_indices.is_empty() {
+            warn!(
+                "Skipping coarse channel index: {} as it has no data for the selected timesteps",
+                coarse_chan_index
+            );
+            continue;
+        }
+
+        // Output what we ended up with
+        for timestep_index in &coarse_chan_timestep_indices {
+            info!(
+                "Coarse channel index: {} timestep: index: {} GPS time: {}",
+                coarse_chan_index,
+                timestep_index,
+                context.timesteps[*timestep_index].gps_time_ms as f64 / 1000.0
+            );
+        }
+
+        autos.push(get_auto_spectra(
+            context,
+            &coarse_chan_timestep_indices,
+            coarse_chan_index,
+            average,
+            output_stats && average,
+            waterfall,
+            cross_pols,
+            van_vleck,
+        )?);
+    }
+
+    Ok(autos)
+}
+
+/// The timesteps of `timestep_indices` which a coarse channel has data for (according to `has_data`), or only the last
+/// of them if `last_timestep_only` is true.
+fn select_timesteps(
+    timestep_indices: &[usize],
+    has_data: impl Fn(usize) -> bool,
+    last_timestep_only: bool,
+) -> Vec<usize> {
+    let with_data = timestep_indices.iter().copied().filter(|t| has_data(*t));
+
+    if last_timestep_only {
+        with_data.last().into_iter().collect()
+    } else {
+        with_data.collect()
+    }
+}
+
+/// Works out the autos (see `get_autocorrelations`) and passes those of each coarse channel to `writer`.
+#[allow(clippy::too_many_arguments)]
 pub fn output_autocorrelations(
     context: &CorrelatorContext,
-    output_dir: &str,
-    use_any_timestep: bool,
-    memory_limit_gb: Option<f32>,
-) {
+    writer: &mut dyn AutosWriter,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    average: bool,
+    output_stats: bool,
+    waterfall: bool,
+    cross_pols: bool,
+    van_vleck: bool,
+) -> Result<(), MwaxStatsError> {
     info!("Starting output_autocorrelations()...");
 
-    // Determine timestep and coarse channel range
-    // For autos we only want the last timestep and one coarse channel
-    let (ts_range, cc_range) =
-        processing::get_timesteps_coarse_chan_ranges(context, use_any_timestep, memory_limit_gb)
-            .unwrap();
+    let autos = get_autocorrelations(
+        context,
+        timestep_range,
+        coarse_chan_range,
+        average,
+        output_stats,
+        waterfall,
+        cross_pols,
+        van_vleck,
+    )?;
 
-    // Get the objects associated with indices
-    let timestep_index = ts_range.end - 1; // range object "end" values are exclusive, so subtract 1!
-    let coarse_chan_index = cc_range.start;
-    let timestep = &context.timesteps[timestep_index];
-    let coarse_chan = &context.coarse_chans[coarse_chan_index];
+    for spectra in &autos {
+        writer.write_autos(spectra)?;
+    }
 
-    // Output what we ended up with
-    info!(
-        "Timestep: index: {} GPS time: {}",
-        timestep_index,
-        timestep.gps_time_ms as f64 / 1000.0
-    );
+    Ok(())
+}
 
+/// Works out the autos of a single coarse channel from the timesteps provided, reading them one at a time (see
+/// `compute_auto_spectra`).
+#[allow(clippy::too_many_arguments)]
+pub fn get_auto_spectra(
+    context: &CorrelatorContext,
+    timestep_indices: &[usize],
+    coarse_chan_index: usize,
+    average: bool,
+    output_stats: bool,
+    waterfall: bool,
+    cross_pols: bool,
+    van_vleck: bool,
+) -> Result<AutoSpectra, MwaxStatsError> {
     info!(
         "Coarse channel: index: {} Rec Chan: {}",
-        coarse_chan_index, coarse_chan.rec_chan_number
+        coarse_chan_index, context.coarse_chans[coarse_chan_index].rec_chan_number
     );
 
-    // Get data info a buffer
-    let data: Vec<f32> = processing::get_data(context, timestep_index, coarse_chan_index);
+    let num_fine_chans = context.metafits_context.num_corr_fine_chans_per_coarse;
+
+    // Establish the starting index for the fine channel frequency array. It is for all channels whether we provided data or not
+    let fine_chan_freq_index = coarse_chan_index * num_fine_chans;
+
+    // Determine fine chan frequencies
+    let freqs_mhz: Vec<f32> = context.metafits_context.metafits_fine_chan_freqs_hz
+        [fine_chan_freq_index..fine_chan_freq_index + num_fine_chans]
+        .iter()
+        .map(|f| (f / 1000000.0) as f32)
+        .collect();
+
+    // Get the data of each timestep into a buffer, only as it is needed
+    let timestep_data = timestep_indices.iter().map(|timestep_index| {
+        if van_vleck {
+            processing::get_van_vleck_corrected_data(context, *timestep_index, coarse_chan_index)
+        } else {
+            processing::get_data(context, *timestep_index, coarse_chan_index)
+        }
+    });
+
+    compute_auto_spectra(
+        &RawDataLayout::from_context(context),
+        timestep_data,
+        timestep_indices,
+        coarse_chan_index,
+        freqs_mhz,
+        van_vleck,
+        average,
+        output_stats,
+        waterfall,
+        cross_pols,
+    )
+}
+
+/// Works out the autos of a single coarse channel from `timestep_data`, the raw data (see `RawDataLayout`) of each of
+/// `timestep_indices` in turn. Only one timestep is needed at a time, so `timestep_data` can read them as they are
+/// asked for; its first error is returned. An empty `timestep_indices`, or a `timestep_data` with fewer timesteps than
+/// that, is an `InvalidArgument` error.
+///
+/// If `average` is false, only the last timestep is used for the spectra (and stats and cross pols). `van_vleck` is
+/// whether the data has been Van Vleck corrected.
+#[allow(clippy::too_many_arguments)]
+pub fn compute_auto_spectra<I>(
+    layout: &RawDataLayout,
+    timestep_data: I,
+    timestep_indices: &[usize],
+    coarse_chan_index: usize,
+    freqs_mhz: Vec<f32>,
+    van_vleck: bool,
+    average: bool,
+    output_stats: bool,
+    waterfall: bool,
+    cross_pols: bool,
+) -> Result<AutoSpectra, MwaxStatsError>
+where
+    I: IntoIterator<Item = Result<Vec<f32>, MwaxStatsError>>,
+{
+    if timestep_indices.is_empty() {
+        return Err(MwaxStatsError::InvalidArgument(format!(
+            "No timesteps to compute the autos of coarse channel {} from",
+            coarse_chan_index
+        )));
+    }
+
+    let num_ants = layout.num_ants;
+    let num_fine_chans = layout.num_fine_chans;
+
+    let mut accumulator = AutosAccumulator::new(num_ants, num_fine_chans);
+
+    // The waterfall is [ant][timestep][fine chan] (dB) for each pol
+    let mut waterfall_db = waterfall.then(|| {
+        let shape = (num_ants, timestep_indices.len(), num_fine_chans);
+        (Array3::<f32>::zeros(shape), Array3::<f32>::zeros(shape))
+    });
+
+    // Sum of the complex XY and YX, [ant][fine chan][pol]
+    let mut cross_pol_sum =
+        cross_pols.then(|| Array3::<Complex<f64>>::zeros((num_ants, num_fine_chans, 2)));
 
-    // Open a file for writing
-    let output_filename = Path::new(output_dir).join(format!(
-        "{}_autos_{}chans_{}T_ch{}.dat",
-        context.metafits_context.obs_id,
-        context.metafits_context.num_corr_fine_chans_per_coarse,
-        context.metafits_context.num_ants,
-        coarse_chan.rec_chan_number
-    ));
+    let mut num_timesteps_read = 0;
+    for (timestep_loop_index, data) in timestep_data
+        .into_iter()
+        .take(timestep_indices.len())
+        .enumerate()
+    {
+        let data = data?;
+        num_timesteps_read += 1;
+        let powers = get_auto_powers(layout, &data);
 
-    let mut output_file =
-        File::create(&output_filename).expect("Unable to open autos file for writing");
+        if average || timestep_loop_index == timestep_indices.len() - 1 {
+            accumulator.add(&powers);
+
+            if let Some(cross_pol_sum) = cross_pol_sum.as_mut() {
+                *cross_pol_sum += &get_auto_cross_pols(layout, &data)
+                    .mapv(|c| Complex::new(c.re as f64, c.im as f64));
+            }
+        }
+
+        if let Some((xx_db, yy_db)) = waterfall_db.as_mut() {
+            for (pol, pol_db) in [(XX, xx_db), (YY, yy_db)] {
+                pol_db
+                    .slice_mut(s![.., timestep_loop_index, ..])
+                    .assign(&powers.index_axis(Axis(2), pol).mapv(power_to_db));
+            }
+        }
+    }
+
+    if num_timesteps_read < timestep_indices.len() {
+        return Err(MwaxStatsError::InvalidArgument(format!(
+            "Only {} of the {} timesteps of coarse channel {} could be read",
+            num_timesteps_read,
+            timestep_indices.len(),
+            coarse_chan_index
+        )));
+    }
+
+    let mean_db = accumulator.mean_db();
+
+    let stats = output_stats.then(|| {
+        let stddev_db = accumulator.stddev_db();
+        AutoStats {
+            xx_min_db: accumulator.min_db.index_axis(Axis(2), XX).to_owned(),
+            xx_max_db: accumulator.max_db.index_axis(Axis(2), XX).to_owned(),
+            xx_stddev_db: stddev_db.index_axis(Axis(2), XX).to_owned(),
+            yy_min_db: accumulator.min_db.index_axis(Axis(2), YY).to_owned(),
+            yy_max_db: accumulator.max_db.index_axis(Axis(2), YY).to_owned(),
+            yy_stddev_db: stddev_db.index_axis(Axis(2), YY).to_owned(),
+        }
+    });
+
+    let cross_pols = cross_pol_sum.map(|cross_pol_sum| {
+        let cross_pol_mean = cross_pol_sum.mapv(|c| c / accumulator.num_timesteps as f64);
+        let pol_db = |pol: usize| {
+            cross_pol_mean
+                .index_axis(Axis(2), pol)
+                .mapv(|c| power_to_db(c.norm() as f32))
+        };
+        let pol_phase_deg = |pol: usize| {
+            cross_pol_mean
+                .index_axis(Axis(2), pol)
+                .mapv(|c| c.arg().to_degrees() as f32)
+        };
+        AutoCrossPols {
+            xy_db: pol_db(XY),
+            xy_phase_deg: pol_phase_deg(XY),
+            yx_db: pol_db(YX),
+            yx_phase_deg: pol_phase_deg(YX),
+        }
+    });
+
+    let waterfall = waterfall_db.map(|(xx_db, yy_db)| AutoWaterfall {
+        timestep_indices: timestep_indices.to_vec(),
+        xx_db,
+        yy_db,
+    });
+
+    Ok(AutoSpectra {
+        coarse_chan_index,
+        timestep_indices: if average {
+            timestep_indices.to_vec()
+        } else {
+            timestep_indices[timestep_indices.len() - 1..].to_vec()
+        },
+        corrections: Corrections {
+            van_vleck,
+            ..Default::default()
+        },
+        freqs_mhz,
+        xx_db: mean_db.index_axis(Axis(2), XX).to_owned(),
+        yy_db: mean_db.index_axis(Axis(2), YY).to_owned(),
+        stats,
+        cross_pols,
+        waterfall,
+    })
+}
+
+/// Extracts the (linear) XX and YY power of each antenna from one timestep of raw data.
+///
+/// Returns an array of [ant][fine chan][pol] where pol is XX or YY.
+fn get_auto_powers(layout: &RawDataLayout, data: &[f32]) -> Array3<f32> {
+    let mut powers = Array3::<f32>::zeros((layout.num_ants, layout.num_fine_chans, 2));
 
     // Loop through all of the baselines
-    for (bl_index, bl) in context.metafits_context.baselines.iter().enumerate() {
+    for (bl_index, bl) in layout.baselines.iter().enumerate() {
         // We only care about auto correlations
         if bl.ant1_index == bl.ant2_index {
-            // Establish the starting index for the fine channel frequency array. It is for all channels whether we provided data or not
-            let fine_chan_freq_index =
-                coarse_chan_index * context.metafits_context.num_corr_fine_chans_per_coarse;
-
             // Establish the index to this baseline in the data vector
-            let mut data_index: usize = bl_index
-                * (context.metafits_context.num_corr_fine_chans_per_coarse
-                    * context.metafits_context.num_visibility_pols
-                    * 2);
+            let mut data_index: usize = bl_index * layout.num_fine_chans * FLOATS_PER_FINE_CHAN;
 
             // Loop through fine channels
-            for fine_chan in 0..context.metafits_context.num_corr_fine_chans_per_coarse {
-                // Calculate Power in X and Y
+            for fine_chan in 0..layout.num_fine_chans {
+                // Get Power in X and Y
                 // data for each fine channel is: xx_r, xx_i, xy_r, xy_i, yx_r, yx_i, yy_r, yy_i
                 let xx_r = data[data_index];
                 let yy_r = data[data_index + 6];
-                let xx_pow: f32 = 10.0 * f32::log10(xx_r + 1.0);
-                let yy_pow: f32 = 10.0 * f32::log10(yy_r + 1.0);
-
-                // Determine fine chan frequency
-                let fine_chan_freq_mhz = (&context.metafits_context.metafits_fine_chan_freqs_hz
-                    [fine_chan_freq_index + fine_chan]
-                    / 1000000.0) as f32;
 
                 trace!(
-                    "ant: {} fine_chan_freq_index {} finech: {} freq: {} MHz xx_r: {} yy_r: {} xx_pow: {} yy_pow: {}",
-                    bl.ant1_index, fine_chan_freq_index, fine_chan, fine_chan_freq_mhz, xx_r, yy_r, xx_pow, yy_pow
+                    "ant: {} finech: {} xx_r: {} yy_r: {}",
+                    bl.ant1_index,
+                    fine_chan,
+                    xx_r,
+                    yy_r
                 );
 
-                // Write data to file
-                output_file
-                    .write_f32(fine_chan_freq_mhz)
-                    .expect("Error writing fine_chan_freq_MHz data");
-                output_file
-                    .write_f32(xx_pow)
-                    .expect("Error writing xx_pow data");
-                output_file
-                    .write_f32(yy_pow)
-                    .expect("Error writing yy_pow data");
+                powers[[bl.ant1_index, fine_chan, XX]] = xx_r;
+                powers[[bl.ant1_index, fine_chan, YY]] = yy_r;
 
                 // Determine index of next data
                 // [bl][ch][pol][r/i]
                 // increment from the start of the baseline along the fine channels
                 // Each fine channel has 4 pols and 2 values
-                data_index += context.metafits_context.num_visibility_pols * 2;
+                data_index += FLOATS_PER_FINE_CHAN;
             }
         }
     }
 
-    info!(
-        "Done! {} written.",
-        &output_filename
-            .into_os_string()
-            .to_str()
-            .expect("Could not convert path into string")
-    );
+    powers
+}
+
+/// Extracts the complex XY and YX of each antenna's autocorrelation from one timestep of raw data.
+///
+/// Returns an array of [ant][fine chan][pol] where pol is XY or YX.
+fn get_auto_cross_pols(layout: &RawDataLayout, data: &[f32]) -> Array3<Complex<f32>> {
+    let mut cross_pols = Array3::<Complex<f32>>::zeros((layout.num_ants, layout.num_fine_chans, 2));
+
+    for (bl_index, bl) in layout.baselines.iter().enumerate() {
+        // We only care about auto correlations
+        if bl.ant1_index == bl.ant2_index {
+            for fine_chan in 0..layout.num_fine_chans {
+                // data for each fine channel is: xx_r, xx_i, xy_r, xy_i, yx_r, yx_i, yy_r, yy_i
+                let data_index =
+                    (bl_index * layout.num_fine_chans + fine_chan) * FLOATS_PER_FINE_CHAN;
+
+                cross_pols[[bl.ant1_index, fine_chan, XY]] =
+                    Complex::new(data[data_index + 2], data[data_index + 3]);
+                cross_pols[[bl.ant1_index, fine_chan, YX]] =
+                    Complex::new(data[data_index + 4], data[data_index + 5]);
+            }
+        }
+    }
+
+    cross_pols
+}
+
+/// Converts a linear power to dB
+fn power_to_db(power: f32) -> f32 {
+    10.0 * f32::log10(power + 1.0)
+}
+
+/// Accumulates the power of each [ant][fine chan][pol] over a number of timesteps so we can output
+/// the mean, min, max and standard deviation.
+struct AutosAccumulator {
+    num_timesteps: usize,
+    sum_linear: Array3<f64>,
+    sum_db: Array3<f64>,
+    sum_db_squared: Array3<f64>,
+    min_db: Array3<f32>,
+    max_db: Array3<f32>,
+}
+
+impl AutosAccumulator {
+    fn new(num_ants: usize, num_fine_chans: usize) -> Self {
+        let shape = (num_ants, num_fine_chans, 2);
+
+        Self {
+            num_timesteps: 0,
+            sum_linear: Array3::zeros(shape),
+            sum_db: Array3::zeros(shape),
+            sum_db_squared: Array3::zeros(shape),
+            min_db: Array3::from_elem(shape, f32::INFINITY),
+            max_db: Array3::from_elem(shape, f32::NEG_INFINITY),
+        }
+    }
+
+    /// Add one timestep of linear powers
+    fn add(&mut self, powers: &Array3<f32>) {
+        for (index, power) in powers.indexed_iter() {
+            let power_db = power_to_db(*power);
+
+            self.sum_linear[index] += *power as f64;
+            self.sum_db[index] += power_db as f64;
+            self.sum_db_squared[index] += (power_db as f64).powi(2);
+            self.min_db[index] = self.min_db[index].min(power_db);
+            self.max_db[index] = self.max_db[index].max(power_db);
+        }
+
+        self.num_timesteps += 1;
+    }
+
+    /// The mean power (dB). We average the linear power and then convert to dB.
+    fn mean_db(&self) -> Array3<f32> {
+        self.sum_linear
+            .mapv(|s| power_to_db((s / self.num_timesteps as f64) as f32))
+    }
+
+    /// The standard deviation of the power (dB)
+    fn stddev_db(&self) -> Array3<f32> {
+        let n = self.num_timesteps as f64;
+
+        let mut stddev = Array3::<f32>::zeros(self.sum_db.dim());
+
+        for (index, s) in stddev.indexed_iter_mut() {
+            let mean = self.sum_db[index] / n;
+            // Guard against tiny negative values due to rounding
+            *s = (self.sum_db_squared[index] / n - mean.powi(2))
+                .max(0.0)
+                .sqrt() as f32;
+        }
+
+        stddev
+    }
+}
+
+/// Writes the autos as one or more binary files per coarse channel.
+///
+/// Each file is named OBSID_autos_FINECHANSchans_128T_chRECCHAN.dat  (128 is the number of tiles which may vary)
+///
+/// File format 3 floats * num fine channels per coarse * tiles:
+/// Slowest moving -> fastest moving
+/// [ant][fine chan freq][XX][YY]
+///
+///     fine chan freq (MHz)
+///     XX pow (dB)
+///     YY pow (dB)
+///
+/// If there are stats, a second file named OBSID_autos_stats_FINECHANSchans_128T_chRECCHAN.dat is written containing
+/// 7 floats * num fine channels per coarse * tiles:
+/// [ant][fine chan freq][XX min][XX max][XX stddev][YY min][YY max][YY stddev]
+///
+///     fine chan freq (MHz)
+///     XX min, max and stddev pow (dB)
+///     YY min, max and stddev pow (dB)
+///
+/// If there is a waterfall, a file named OBSID_autos_waterfall_FINECHANSchans_128T_NTIMESTEPSts_chRECCHAN.dat is also
+/// written containing 3 floats * num fine channels per coarse * timesteps * tiles:
+/// [ant][timestep][fine chan freq][XX][YY]
+///
+///     fine chan freq (MHz)
+///     XX pow (dB)
+///     YY pow (dB)
+///
+/// If there are cross pols, a file named OBSID_autos_crosspol_FINECHANSchans_128T_chRECCHAN.dat is also written
+/// containing 5 floats * num fine channels per coarse * tiles, using the same timestep(s) as the autos file:
+/// [ant][fine chan freq][XY pow][XY phase][YX pow][YX phase]
+///
+///     fine chan freq (MHz)
+///     XY pow (dB)
+///     XY phase (deg)
+///     YX pow (dB)
+///     YX phase (deg)
+///
+/// With `OutputFormat::Header` every file starts with a `FileHeader` describing it.
+pub struct AutosFileWriter {
+    obs: ObsInfo,
+    output_dir: PathBuf,
+    output_format: OutputFormat,
+}
+
+impl AutosFileWriter {
+    pub fn new(obs: ObsInfo, output_dir: &str, output_format: OutputFormat) -> Self {
+        AutosFileWriter {
+            obs,
+            output_dir: PathBuf::from(output_dir),
+            output_format,
+        }
+    }
+}
+
+impl AutosWriter for AutosFileWriter {
+    fn write_autos(&mut self, spectra: &AutoSpectra) -> Result<(), MwaxStatsError> {
+        let obs = &self.obs;
+        let rec_chan_number = obs.rec_chan_numbers[spectra.coarse_chan_index];
+        let filename = |product: &str| {
+            self.output_dir.join(format!(
+                "{}_{}_{}chans_{}T_ch{}.dat",
+                obs.obs_id, product, obs.num_fine_chans, obs.num_ants, rec_chan_number
+            ))
+        };
+
+        // The header of each file, from the timesteps it was made from
+        let header = |timestep_indices: &[usize], record_layout: RecordLayout| {
+            (self.output_format == OutputFormat::Header)
+                .then(|| {
+                    FileHeader::new(
+                        obs,
+                        spectra.coarse_chan_index,
+                        timestep_indices,
+                        record_layout,
+                        &spectra.corrections,
+                    )
+                })
+                .transpose()
+        };
+
+        // Write the (mean) power
+        write_autos_file(
+            &filename("autos"),
+            header(&spectra.timestep_indices, RecordLayout::Autos)?,
+            &spectra.freqs_mhz,
+            &[spectra.xx_db.view(), spectra.yy_db.view()],
+        )?;
+
+        // Optionally write the min/max/stddev
+        if let Some(stats) = &spectra.stats {
+            write_autos_file(
+                &filename("autos_stats"),
+                header(&spectra.timestep_indices, RecordLayout::AutosStats)?,
+                &spectra.freqs_mhz,
+                &[
+                    stats.xx_min_db.view(),
+                    stats.xx_max_db.view(),
+                    stats.xx_stddev_db.view(),
+                    stats.yy_min_db.view(),
+                    stats.yy_max_db.view(),
+                    stats.yy_stddev_db.view(),
+                ],
+            )?;
+        }
+
+        // Optionally write the cross pols
+        if let Some(cross_pols) = &spectra.cross_pols {
+            write_autos_file(
+                &filename("autos_crosspol"),
+                header(&spectra.timestep_indices, RecordLayout::AutosCrossPols)?,
+                &spectra.freqs_mhz,
+                &[
+                    cross_pols.xy_db.view(),
+                    cross_pols.xy_phase_deg.view(),
+                    cross_pols.yx_db.view(),
+                    cross_pols.yx_phase_deg.view(),
+                ],
+            )?;
+        }
+
+        // Optionally write the waterfall
+        if let Some(waterfall) = &spectra.waterfall {
+            let waterfall_filename = self.output_dir.join(format!(
+                "{}_autos_waterfall_{}chans_{}T_{}ts_ch{}.dat",
+                obs.obs_id,
+                obs.num_fine_chans,
+                obs.num_ants,
+                waterfall.timestep_indices.len(),
+                rec_chan_number
+            ));
+
+            write_autos_waterfall_file(
+                &waterfall_filename,
+                header(&waterfall.timestep_indices, RecordLayout::AutosWaterfall)?,
+                &spectra.freqs_mhz,
+                waterfall.xx_db.view(),
+                waterfall.yy_db.view(),
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Writes an autos file, starting with the `header` if there is one. For each antenna and fine channel we write the
+/// frequency followed by the value from each of `columns` (each indexed [ant][fine chan]).
+pub(crate) fn write_autos_file(
+    output_filename: &Path,
+    header: Option<FileHeader>,
+    fine_chan_freqs_mhz: &[f32],
+    columns: &[ArrayView2<f32>],
+) -> Result<(), MwaxStatsError> {
+    let write = || -> io::Result<()> {
+        let mut output_file = BufWriter::new(File::create(output_filename)?);
+
+        if let Some(header) = header {
+            header.write(&mut output_file)?;
+        }
+
+        write_autos_records(&mut output_file, fine_chan_freqs_mhz, columns)?;
+
+        io::Write::flush(&mut output_file)
+    };
+
+    write().map_err(|e| MwaxStatsError::write_file(output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+/// Writes the records of an autos file: for each antenna and fine channel the frequency followed by the value from
+/// each of `columns` (each indexed [ant][fine chan]).
+pub(crate) fn write_autos_records<W: io::Write>(
+    writer: &mut W,
+    fine_chan_freqs_mhz: &[f32],
+    columns: &[ArrayView2<f32>],
+) -> io::Result<()> {
+    for ant in 0..columns[0].dim().0 {
+        for (fine_chan, fine_chan_freq_mhz) in fine_chan_freqs_mhz.iter().enumerate() {
+            writer.write_f32(*fine_chan_freq_mhz)?;
+
+            for column in columns {
+                writer.write_f32(column[[ant, fine_chan]])?;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes an autos waterfall file, starting with the `header` if there is one. For each antenna, timestep and fine
+/// channel we write the frequency followed by the XX and YY power (each indexed [ant][timestep][fine chan]).
+pub(crate) fn write_autos_waterfall_file(
+    output_filename: &Path,
+    header: Option<FileHeader>,
+    fine_chan_freqs_mhz: &[f32],
+    xx_db: ArrayView3<f32>,
+    yy_db: ArrayView3<f32>,
+) -> Result<(), MwaxStatsError> {
+    let write = || -> io::Result<()> {
+        let mut output_file = BufWriter::new(File::create(output_filename)?);
+
+        if let Some(header) = header {
+            header.write(&mut output_file)?;
+        }
+
+        write_autos_waterfall_records(&mut output_file, fine_chan_freqs_mhz, xx_db, yy_db)?;
+
+        io::Write::flush(&mut output_file)
+    };
+
+    write().map_err(|e| MwaxStatsError::write_file(output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+/// Writes the records of an autos waterfall file: for each antenna, timestep and fine channel the frequency followed
+/// by the XX and YY power (each indexed [ant][timestep][fine chan]).
+pub(crate) fn write_autos_waterfall_records<W: io::Write>(
+    writer: &mut W,
+    fine_chan_freqs_mhz: &[f32],
+    xx_db: ArrayView3<f32>,
+    yy_db: ArrayView3<f32>,
+) -> io::Result<()> {
+    for ((ant, timestep, fine_chan), xx) in xx_db.indexed_iter() {
+        writer.write_f32(fine_chan_freqs_mhz[fine_chan])?;
+        writer.write_f32(*xx)?;
+        writer.write_f32(yy_db[[ant, timestep, fine_chan]])?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BASELINES: [Baseline; 3] = [
+        Baseline {
+            ant1_index: 0,
+            ant2_index: 0,
+        },
+        Baseline {
+            ant1_index: 0,
+            ant2_index: 1,
+        },
+        Baseline {
+            ant1_index: 1,
+            ant2_index: 1,
+        },
+    ];
+    const FREQS_MHZ: [f32; 2] = [150.0, 150.01];
+
+    fn get_layout() -> RawDataLayout<'static> {
+        RawDataLayout {
+            baselines: &BASELINES,
+            num_ants: 2,
+            num_fine_chans: 2,
+        }
+    }
+
+    /// Three timesteps of raw data. Tile 0's XX is 9, 99 and 999 (10, 20 and 30 dB) and its YY is always 9, tile 1's XX
+    /// is always 9 and its YY is 99. The XY of each timestep is (1, timestep) and the YX is (timestep, -1). The cross
+    /// correlation is nonsense which should not be used.
+    fn get_raw_data() -> Vec<Vec<f32>> {
+        let layout = get_layout();
+        (0..3)
+            .map(|timestep| {
+                let mut data = Vec::new();
+                for bl in layout.baselines {
+                    for _ in 0..layout.num_fine_chans {
+                        let (xx, yy) = match (bl.ant1_index, bl.ant2_index) {
+                            (0, 0) => (10f32.powi(timestep + 1) - 1.0, 9.0),
+                            (1, 1) => (9.0, 99.0),
+                            _ => (1e6, 1e6),
+                        };
+                        let t = timestep as f32;
+                        data.extend([xx, 0.0, 1.0, t, t, -1.0, yy, 0.0]);
+                    }
+                }
+                data
+            })
+            .collect()
+    }
+
+    fn compute(average: bool, output_stats: bool) -> AutoSpectra {
+        compute_auto_spectra(
+            &get_layout(),
+            get_raw_data().into_iter().map(Ok),
+            &[5, 6, 7],
+            3,
+            FREQS_MHZ.to_vec(),
+            false,
+            average,
+            output_stats,
+            true,
+            true,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_compute_auto_spectra() {
+        let averaged = compute(true, true);
+        assert_eq!(averaged.coarse_chan_index, 3);
+        assert_eq!(averaged.timestep_indices, vec![5, 6, 7]);
+
+        // The mean of the linear power, (9 + 99 + 999) / 3
+        assert_eq!(averaged.xx_db.dim(), (2, 2));
+        assert_eq!(averaged.xx_db[[0, 1]], power_to_db(369.0));
+        assert_eq!(averaged.yy_db[[0, 1]], 10.0);
+        assert_eq!(averaged.yy_db[[1, 0]], 20.0);
+
+        let stats = averaged.stats.unwrap();
+        assert_eq!(stats.xx_min_db[[0, 0]], 10.0);
+        assert_eq!(stats.xx_max_db[[0, 0]], 30.0);
+        assert!((stats.xx_stddev_db[[0, 0]] - (200f32 / 3.0).sqrt()).abs() < 1e-4);
+        assert_eq!(stats.yy_stddev_db[[1, 1]], 0.0);
+
+        // The mean XY is (1, 1) and YX is (1, -1)
+        let cross_pols = averaged.cross_pols.unwrap();
+        assert!((cross_pols.xy_phase_deg[[1, 0]] - 45.0).abs() < 1e-4);
+        assert!((cross_pols.yx_phase_deg[[1, 0]] + 45.0).abs() < 1e-4);
+        assert_eq!(cross_pols.xy_db[[1, 0]], power_to_db(2f32.sqrt()));
+
+        // The waterfall has every timestep
+        let waterfall = averaged.waterfall.unwrap();
+        assert_eq!(waterfall.timestep_indices, vec![5, 6, 7]);
+        assert_eq!(waterfall.xx_db.dim(), (2, 3, 2));
+        assert_eq!(
+            waterfall.xx_db.slice(s![0, .., 0]).to_vec(),
+            [10.0, 20.0, 30.0]
+        );
+
+        // Without averaging only the last timestep is used, but the waterfall still has them all
+        let last = compute(false, false);
+        assert_eq!(last.timestep_indices, vec![7]);
+        assert_eq!(last.xx_db[[0, 0]], 30.0);
+        assert!(last.stats.is_none());
+        let cross_pols = last.cross_pols.unwrap();
+        assert!((cross_pols.xy_phase_deg[[0, 1]] - 2f32.atan2(1.0).to_degrees()).abs() < 1e-4);
+        assert_eq!(last.waterfall.unwrap().xx_db, waterfall.xx_db);
+
+        // An error reading the data is returned
+        let result = compute_auto_spectra(
+            &get_layout(),
+            vec![
+                Ok(get_raw_data().remove(0)),
+                Err(MwaxStatsError::InvalidArgument("unreadable".to_string())),
+            ],
+            &[5, 6],
+            3,
+            FREQS_MHZ.to_vec(),
+            false,
+            true,
+            false,
+            false,
+            false,
+        );
+        assert!(
+            matches!(result, Err(MwaxStatsError::InvalidArgument(reason)) if reason == "unreadable")
+        );
+    }
+
+    #[test]
+    fn test_compute_auto_spectra_invalid() {
+        let compute_with = |timestep_data: Vec<Vec<f32>>, timestep_indices: &[usize]| {
+            compute_auto_spectra(
+                &get_layout(),
+                timestep_data.into_iter().map(Ok),
+                timestep_indices,
+                3,
+                FREQS_MHZ.to_vec(),
+                false,
+                false,
+                false,
+                true,
+                true,
+            )
+        };
+
+        // No timesteps
+        let result = compute_with(get_raw_data(), &[]);
+        assert!(
+            matches!(result, Err(MwaxStatsError::InvalidArgument(reason)) if reason.contains("No timesteps"))
+        );
+
+        // Fewer timesteps of data than timestep indices
+        let result = compute_with(get_raw_data(), &[5, 6, 7, 8]);
+        assert!(
+            matches!(result, Err(MwaxStatsError::InvalidArgument(reason)) if reason.contains("Only 3 of the 4"))
+        );
+    }
+
+    #[test]
+    fn test_select_timesteps() {
+        // Coarse channel 0 has every timestep but coarse channel 1 is missing the last one
+        let has_data = |coarse_chan_index: usize| move |t: usize| coarse_chan_index == 0 || t != 7;
+        let timestep_indices = [4, 5, 6, 7];
+
+        assert_eq!(select_timesteps(&timestep_indices, has_data(0), true), [7]);
+        assert_eq!(select_timesteps(&timestep_indices, has_data(1), true), [6]);
+        assert_eq!(
+            select_timesteps(&timestep_indices, has_data(1), false),
+            [4, 5, 6]
+        );
+        assert!(select_timesteps(&timestep_indices, |_| false, true).is_empty());
+    }
+
+    #[test]
+    fn test_autos_file_writer() {
+        use crate::reader::{AutosFile, FileName};
+
+        let obs = ObsInfo {
+            obs_id: 1317706936,
+            num_ants: 2,
+            num_fine_chans: 2,
+            corr_int_time_ms: 2000,
+            rec_chan_numbers: vec![100, 101, 102, 103],
+            timestep_gps_times_ms: (0..8).map(|t| 1317706936000 + t * 2000).collect(),
+        };
+
+        // The files written to a directory, read back
+        let write = |spectra: &AutoSpectra, output_format: OutputFormat| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut writer =
+                AutosFileWriter::new(obs.clone(), dir.path().to_str().unwrap(), output_format);
+            writer.write_autos(spectra).unwrap();
+
+            let mut files: Vec<(String, AutosFile)> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| {
+                    let path = entry.unwrap().path();
+                    let filename = path.file_name().unwrap().to_str().unwrap().to_string();
+                    (filename, AutosFile::read(&path).unwrap())
+                })
+                .collect();
+            files.sort_by(|a, b| a.0.cmp(&b.0));
+            files
+        };
+
+        // Averaged, with a header and every extra file
+        let averaged = compute(true, true);
+        let files = write(&averaged, OutputFormat::Header);
+        let filenames: Vec<&str> = files.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(
+            filenames,
+            [
+                "1317706936_autos_2chans_2T_ch103.dat",
+                "1317706936_autos_crosspol_2chans_2T_ch103.dat",
+                "1317706936_autos_stats_2chans_2T_ch103.dat",
+                "1317706936_autos_waterfall_2chans_2T_3ts_ch103.dat",
+            ]
+        );
+        for (filename, autos_file) in &files {
+            let name = FileName::parse(filename).unwrap();
+            let header = autos_file.header.unwrap();
+            assert_eq!(header.record_layout, name.record_layout);
+            assert_eq!(header.rec_chan_number, 103);
+            // Timesteps 5 to 7
+            assert_eq!(header.num_timesteps, 3);
+            assert_eq!(header.start_gps_time_ms, 1317706946000);
+            assert_eq!(header.end_gps_time_ms, 1317706952000);
+            assert_eq!(autos_file.freqs_mhz.to_vec(), FREQS_MHZ);
+        }
+
+        let (_, autos) = &files[0];
+        assert_eq!(autos.values.dim(), (2, 1, 2, 2));
+        assert_eq!(autos.values.slice(s![.., 0, .., 0]), averaged.xx_db);
+        assert_eq!(autos.values.slice(s![.., 0, .., 1]), averaged.yy_db);
+
+        let (_, cross_pols) = &files[1];
+        let averaged_cross_pols = averaged.cross_pols.as_ref().unwrap();
+        assert_eq!(
+            cross_pols.values.slice(s![.., 0, .., 1]),
+            averaged_cross_pols.xy_phase_deg
+        );
+
+        let (_, stats) = &files[2];
+        let averaged_stats = averaged.stats.as_ref().unwrap();
+        assert_eq!(stats.values.dim(), (2, 1, 2, 6));
+        assert_eq!(
+            stats.values.slice(s![.., 0, .., 1]),
+            averaged_stats.xx_max_db
+        );
+        assert_eq!(
+            stats.values.slice(s![.., 0, .., 5]),
+            averaged_stats.yy_stddev_db
+        );
+
+        // The waterfall of each tile (the autos are baselines 0 and 2) and timestep is straight from the raw data
+        let (_, waterfall) = &files[3];
+        assert_eq!(waterfall.values.dim(), (2, 3, 2, 2));
+        let data = get_raw_data();
+        for (ant, bl) in [(0, 0), (1, 2)] {
+            for (timestep, timestep_data) in data.iter().enumerate() {
+                for fine_chan in 0..FREQS_MHZ.len() {
+                    let index = (bl * FREQS_MHZ.len() + fine_chan) * FLOATS_PER_FINE_CHAN;
+                    assert_eq!(
+                        waterfall.values[[ant, timestep, fine_chan, 0]],
+                        10.0 * (timestep_data[index] + 1.0).log10()
+                    );
+                    assert_eq!(
+                        waterfall.values[[ant, timestep, fine_chan, 1]],
+                        10.0 * (timestep_data[index + 6] + 1.0).log10()
+                    );
+                }
+            }
+        }
+
+        // The last timestep only, as legacy files without stats
+        let last = compute(false, false);
+        let files = write(&last, OutputFormat::Legacy);
+        let filenames: Vec<&str> = files.iter().map(|(f, _)| f.as_str()).collect();
+        assert_eq!(
+            filenames,
+            [
+                "1317706936_autos_2chans_2T_ch103.dat",
+                "1317706936_autos_crosspol_2chans_2T_ch103.dat",
+                "1317706936_autos_waterfall_2chans_2T_3ts_ch103.dat",
+            ]
+        );
+        assert!(files
+            .iter()
+            .all(|(_, autos_file)| autos_file.header.is_none()));
+        assert_eq!(files[0].1.values.slice(s![.., 0, .., 0]), last.xx_db);
+        assert_eq!(files[0].1.values[[0, 0, 0, 0]], 30.0);
+    }
+
+    #[test]
+    fn test_power_to_db() {
+        assert_eq!(power_to_db(0.0), 0.0);
+        assert_eq!(power_to_db(9.0), 10.0);
+    }
+
+    #[test]
+    fn test_autos_accumulator() {
+        let mut accumulator = AutosAccumulator::new(1, 2);
+
+        // Two timesteps, powers chosen to be 10 and 20 dB once we add 1
+        accumulator.add(&Array3::from_shape_vec((1, 2, 2), vec![9.0, 99.0, 9.0, 9.0]).unwrap());
+        accumulator.add(&Array3::from_shape_vec((1, 2, 2), vec![99.0, 99.0, 9.0, 9.0]).unwrap());
+
+        // Mean is done in linear space (54 + 1 = 55)
+        assert_eq!(accumulator.mean_db()[[0, 0, XX]], power_to_db(54.0));
+        assert_eq!(accumulator.mean_db()[[0, 0, YY]], 20.0);
+
+        // Min/max/stddev are of the dB values
+        assert_eq!(accumulator.min_db[[0, 0, XX]], 10.0);
+        assert_eq!(accumulator.max_db[[0, 0, XX]], 20.0);
+        assert!((accumulator.stddev_db()[[0, 0, XX]] - 5.0).abs() < 1e-5);
+        assert_eq!(accumulator.stddev_db()[[0, 1, YY]], 0.0);
+    }
 }
@@ -0,0 +1,340 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use crate::processing::CorrectedData;
+use birli::marlu::Complex;
+use core::ops::Range;
+use log::{info, trace};
+use mwalib::CorrelatorContext;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// The closure phase of one triad/polarisation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClosurePhase {
+    /// Phase (deg) of the bispectrum V(a,b) * V(b,c) * V(c,a), vector averaged over timesteps and fine channels
+    pub phase_deg: f32,
+    /// Number of (timestep, fine channel) samples which contributed to the average
+    pub num_samples: u32,
+}
+
+/// Running vector sum of the bispectrum of one triad/polarisation, so closure phases can be built up one chunk of
+/// timesteps at a time
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct BispectrumSum {
+    pub sum: Complex<f64>,
+    pub num_samples: u32,
+}
+
+impl BispectrumSum {
+    /// The closure phase of the vector averaged bispectrum (NaN if there were no usable samples)
+    pub fn closure_phase(&self) -> ClosurePhase {
+        ClosurePhase {
+            phase_deg: if self.num_samples > 0 {
+                self.sum.arg().to_degrees() as f32
+            } else {
+                f32::NAN
+            },
+            num_samples: self.num_samples,
+        }
+    }
+}
+
+/// Parses a triad given on the command line as three antenna indices separated by dashes, e.g. "0-1-2".
+pub fn parse_triad(triad: &str) -> Result<[usize; 3], String> {
+    let ants: Vec<usize> = triad
+        .split('-')
+        .map(|a| a.trim().parse::<usize>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| format!("Invalid closure triad '{}': {}", triad, e))?;
+
+    match ants[..] {
+        [a, b, c] if a != b && b != c && a != c => Ok([a, b, c]),
+        _ => Err(format!(
+            "Invalid closure triad '{}': expected three different antenna indices e.g. 0-1-2",
+            triad
+        )),
+    }
+}
+
+/// Returns every triad (a < b < c) that can be formed from the antennas in `ants`.
+pub fn triads_from_ants(ants: &[usize]) -> Vec<[usize; 3]> {
+    let mut ants = ants.to_vec();
+    ants.sort_unstable();
+    ants.dedup();
+
+    let mut triads = Vec::new();
+    for (i, a) in ants.iter().enumerate() {
+        for (j, b) in ants.iter().enumerate().skip(i + 1) {
+            for c in ants.iter().skip(j + 1) {
+                triads.push([*a, *b, *c]);
+            }
+        }
+    }
+    triads
+}
+
+/// Returns an error if any antenna of `triads` is not one of the `num_ants` antennas of the observation.
+pub fn check_triads(triads: &[[usize; 3]], num_ants: usize) -> Result<(), MwaxStatsError> {
+    match triads.iter().flatten().find(|ant| **ant >= num_ants) {
+        Some(ant) => Err(MwaxStatsError::InvalidArgument(format!(
+            "Antenna index {} in closure triad is out of range (there are {} antennas)",
+            ant, num_ants
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Outputs one binary file for an observation containing the closure phase of each of `triads`, from the
+/// `bispectra` summed (see `accumulate_bispectra`) over the corrected data of all of the fine channels of the coarse
+/// channels in `coarse_chan_range`.
+///
+/// The file is named OBSID_closures_128T_chFIRST-LAST.dat (128 is the number of tiles which may vary, FIRST and LAST
+/// are the first and last receiver channel numbers)
+///
+/// File format 7 floats * triads:
+/// Slowest moving -> fastest moving
+/// [triad][ant a][ant b][ant c][XX closure phase][YY closure phase][XX num samples][YY num samples]
+///
+///     ant a, ant b, ant c (antenna indices, in the order given)
+///     closure phase(XX) (deg)
+///     closure phase(YY) (deg)
+///     num samples(XX)
+///     num samples(YY)
+///
+/// Triads with no usable samples have a closure phase of NaN.
+pub fn output_closure_phases(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    bispectra: &[[BispectrumSum; 2]],
+    coarse_chan_range: &Range<usize>,
+    triads: &[[usize; 3]],
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_closure_phases()...");
+
+    let num_ants = context.metafits_context.num_ants;
+
+    // Open a file for writing
+    let output_filename = Path::new(output_dir).join(format!(
+        "{}_closures_{}T_ch{}-{}.dat",
+        context.metafits_context.obs_id,
+        num_ants,
+        context.coarse_chans[coarse_chan_range.start].rec_chan_number,
+        context.coarse_chans[coarse_chan_range.end - 1].rec_chan_number
+    ));
+
+    let output_file = File::create(&output_filename)
+        .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+    let mut writer = BufWriter::new(&output_file);
+
+    for ([a, b, c], bispectrum_sums) in triads.iter().zip(bispectra) {
+        let [xx, yy] = bispectrum_sums.map(|b| b.closure_phase());
+
+        trace!(
+            "{},{},{},{},{},{},{}",
+            a,
+            b,
+            c,
+            xx.phase_deg,
+            yy.phase_deg,
+            xx.num_samples,
+            yy.num_samples
+        );
+
+        let float_vec = [
+            *a as f32,
+            *b as f32,
+            *c as f32,
+            xx.phase_deg,
+            yy.phase_deg,
+            xx.num_samples as f32,
+            yy.num_samples as f32,
+        ];
+
+        writer
+            .write_all(
+                &float_vec
+                    .iter()
+                    .flat_map(|f| f.to_le_bytes())
+                    .collect::<Vec<u8>>(),
+            )
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+/// Adds the XX and YY bispectrum of each of `triads` in the corrected `data` to `bispectra`.
+///
+/// For a triad (a, b, c) the bispectrum V(a,b) * V(b,c) * V(c,a) of every timestep and fine channel is summed,
+/// skipping flagged (zero weight) and non-finite samples, so that the closure phase is that of the vector average. Antenna based (gain) errors
+/// cancel in the bispectrum, so for an unresolved source the closure phase should be 0; anything else is baseline
+/// based (or due to source structure).
+pub fn accumulate_bispectra(
+    data: &CorrectedData,
+    num_ants: usize,
+    triads: &[[usize; 3]],
+    bispectra: &mut [[BispectrumSum; 2]],
+) {
+    for ([a, b, c], bispectrum_sums) in triads.iter().zip(bispectra.iter_mut()) {
+        for (pol, bispectrum_sum) in [0, 3].into_iter().zip(bispectrum_sums.iter_mut()) {
+            for (jones_timestep, weights_timestep) in
+                data.jones.outer_iter().zip(data.weights.outer_iter())
+            {
+                for (baselines, weights) in jones_timestep
+                    .outer_iter()
+                    .zip(weights_timestep.outer_iter())
+                {
+                    let vis = |ant1: usize, ant2: usize| {
+                        let (bl, conj) = if ant1 < ant2 {
+                            (get_baseline(ant1, ant2, num_ants), false)
+                        } else {
+                            (get_baseline(ant2, ant1, num_ants), true)
+                        };
+                        let v = baselines[bl][pol];
+                        let v = Complex::new(v.re as f64, v.im as f64);
+                        (weights[bl] > 0.0).then_some(if conj { v.conj() } else { v })
+                    };
+
+                    let (Some(ab), Some(bc), Some(ca)) = (vis(*a, *b), vis(*b, *c), vis(*c, *a))
+                    else {
+                        continue;
+                    };
+                    let bispectrum = ab * bc * ca;
+
+                    if bispectrum.re.is_finite() && bispectrum.im.is_finite() {
+                        bispectrum_sum.sum += bispectrum;
+                        bispectrum_sum.num_samples += 1;
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Returns the baseline index of `ant1` and `ant2` (ant1 <= ant2), in the same order as mwalib's baselines.
+fn get_baseline(ant1: usize, ant2: usize, num_ants: usize) -> usize {
+    // Each antenna before ant1 has (num_ants - ant) baselines
+    ant1 * (2 * num_ants - ant1 + 1) / 2 + (ant2 - ant1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use birli::Jones;
+    use ndarray::Array3;
+
+    #[test]
+    fn test_parse_triad() {
+        assert_eq!(parse_triad("0-1-2"), Ok([0, 1, 2]));
+        assert_eq!(parse_triad("12-3-7"), Ok([12, 3, 7]));
+        assert!(parse_triad("0-1").is_err());
+        assert!(parse_triad("0-1-1").is_err());
+        assert!(parse_triad("0-x-2").is_err());
+    }
+
+    #[test]
+    fn test_triads_from_ants() {
+        assert_eq!(
+            triads_from_ants(&[3, 0, 1, 2]),
+            vec![[0, 1, 2], [0, 1, 3], [0, 2, 3], [1, 2, 3]]
+        );
+        assert!(triads_from_ants(&[0, 1]).is_empty());
+    }
+
+    #[test]
+    fn test_get_baseline() {
+        for (ant1, ant2) in [(0, 0), (0, 3), (1, 1), (2, 3), (3, 3)] {
+            assert_eq!(
+                Some(get_baseline(ant1, ant2, 4)),
+                mwalib::get_baseline_from_antennas(ant1, ant2, 4)
+            );
+        }
+    }
+
+    #[test]
+    fn test_accumulate_bispectra() {
+        let num_ants = 4;
+        let num_baselines = num_ants * (num_ants + 1) / 2;
+        let gains = [
+            Complex::from_polar(1.0f32, 0.0),
+            Complex::from_polar(2.0, 0.5),
+            Complex::from_polar(0.5, -1.0),
+            Complex::from_polar(1.5, 2.0),
+        ];
+
+        // Point source with antenna gains, plus a baseline based error of 30 degrees on 1-2 (YY only)
+        let mut jones_array = Array3::<Jones<f32>>::zeros((2, 3, num_baselines));
+        for mut baselines in jones_array.outer_iter_mut() {
+            for mut vis in baselines.outer_iter_mut() {
+                for ant1 in 0..num_ants {
+                    for ant2 in ant1..num_ants {
+                        let v = gains[ant1] * gains[ant2].conj();
+                        let bl_error = if ant1 == 1 && ant2 == 2 {
+                            Complex::from_polar(1.0, 30.0f32.to_radians())
+                        } else {
+                            Complex::new(1.0, 0.0)
+                        };
+                        vis[get_baseline(ant1, ant2, num_ants)] = Jones::from([
+                            v,
+                            Complex::new(0.0, 0.0),
+                            Complex::new(0.0, 0.0),
+                            v * bl_error,
+                        ]);
+                    }
+                }
+            }
+        }
+        // One non-finite sample and one flagged sample
+        jones_array[[0, 0, get_baseline(0, 1, num_ants)]][0] = Complex::new(f32::NAN, f32::NAN);
+        let mut weights = Array3::<f32>::ones(jones_array.dim());
+        weights[[1, 2, get_baseline(1, 2, num_ants)]] = 0.0;
+
+        let triads = [[0, 1, 2], [2, 1, 0], [0, 1, 3]];
+        assert!(check_triads(&triads, num_ants).is_ok());
+        assert!(check_triads(&[[0, 1, 4]], num_ants).is_err());
+
+        // Accumulate one timestep at a time
+        let mut bispectra = vec![[BispectrumSum::default(); 2]; triads.len()];
+        for timestep in 0..jones_array.dim().0 {
+            accumulate_bispectra(
+                &CorrectedData {
+                    jones: jones_array
+                        .slice(ndarray::s![timestep..timestep + 1, .., ..])
+                        .to_owned(),
+                    weights: weights
+                        .slice(ndarray::s![timestep..timestep + 1, .., ..])
+                        .to_owned(),
+                },
+                num_ants,
+                &triads,
+                &mut bispectra,
+            );
+        }
+        let closures: Vec<[ClosurePhase; 2]> = bispectra
+            .iter()
+            .map(|b| b.map(|b| b.closure_phase()))
+            .collect();
+
+        let [xx, yy] = closures[0];
+        assert!(xx.phase_deg.abs() < 1e-3);
+        assert_eq!(xx.num_samples, 4);
+        assert!((yy.phase_deg - 30.0).abs() < 1e-3);
+        assert_eq!(yy.num_samples, 5);
+
+        // Reversing the triad reverses the sign
+        assert!((closures[1][1].phase_deg + 30.0).abs() < 1e-3);
+
+        // Not affected by the error on 1-2
+        assert!(closures[2][1].phase_deg.abs() < 1e-3);
+    }
+}
@@ -0,0 +1,256 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use birli::Jones;
+use core::ops::Range;
+use log::{info, trace};
+use mwalib::CorrelatorContext;
+use ndarray::{Array2, ArrayView1, Axis};
+use rayon::prelude::*;
+use rustfft::{num_complex::Complex, Fft, FftPlanner};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+/// How many times finer than 1/bandwidth the delay spectrum is sampled
+const DELAY_OVERSAMPLING: usize = 8;
+
+/// The result of fitting a delay to one baseline/polarisation
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DelayFit {
+    /// Delay (ns). The phase of the visibility is 2 * pi * freq * delay
+    pub delay_ns: f32,
+    /// Peak of the delay spectrum divided by the sum of the visibility amplitudes. 1.0 is a perfectly linear phase
+    /// slope, values near 0 mean there was no coherent delay found
+    pub quality: f32,
+}
+
+/// Outputs one binary file for an observation containing the delay of each baseline, fitted across all of the fine
+/// channels of the coarse channels in `coarse_chan_range` of the time `averaged` corrected data (fine_chan, baseline).
+///
+/// The file is named OBSID_delays_128T_chFIRST-LAST.dat (128 is the number of tiles which may vary, FIRST and LAST
+/// are the first and last receiver channel numbers)
+///
+/// File format 4 floats * baselines:
+/// Slowest moving -> fastest moving
+/// [ant1][ant2][XX delay][XX quality][YY delay][YY quality]
+///
+///     delay(XX) (ns)
+///     quality(XX) (0..1)
+///     delay(YY) (ns)
+///     quality(YY) (0..1)
+///
+/// Autocorrelations are written as NaN.
+pub fn output_delays(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    averaged: &Array2<Jones<f32>>,
+    coarse_chan_range: &Range<usize>,
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_delays()...");
+
+    let fine_chans_per_coarse = context.metafits_context.num_corr_fine_chans_per_coarse;
+
+    // Get the frequency of each fine channel in the jones array
+    let fine_chan_freq_index = coarse_chan_range.start * fine_chans_per_coarse;
+    let fine_chan_freqs_hz = &context.metafits_context.metafits_fine_chan_freqs_hz
+        [fine_chan_freq_index..fine_chan_freq_index + averaged.dim().0];
+
+    let delays = fit_delays(
+        averaged,
+        fine_chan_freqs_hz,
+        context.metafits_context.corr_fine_chan_width_hz as f64,
+    );
+
+    // Open a file for writing
+    let output_filename = Path::new(output_dir).join(format!(
+        "{}_delays_{}T_ch{}-{}.dat",
+        context.metafits_context.obs_id,
+        context.metafits_context.num_ants,
+        context.coarse_chans[coarse_chan_range.start].rec_chan_number,
+        context.coarse_chans[coarse_chan_range.end - 1].rec_chan_number
+    ));
+
+    let output_file = File::create(&output_filename)
+        .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+    let mut writer = BufWriter::new(&output_file);
+
+    for (bl, [xx, yy]) in context.metafits_context.baselines.iter().zip(delays) {
+        let float_vec: Vec<f32> = if bl.ant1_index == bl.ant2_index {
+            vec![f32::NAN; 4]
+        } else {
+            trace!(
+                "{},{},{},{},{},{}",
+                bl.ant1_index,
+                bl.ant2_index,
+                xx.delay_ns,
+                xx.quality,
+                yy.delay_ns,
+                yy.quality
+            );
+            vec![xx.delay_ns, xx.quality, yy.delay_ns, yy.quality]
+        };
+
+        writer
+            .write_all(
+                &float_vec
+                    .iter()
+                    .flat_map(|f| f.to_le_bytes())
+                    .collect::<Vec<u8>>(),
+            )
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+/// Fits a delay to the XX and YY of every baseline in `averaged` (fine_chan, baseline), the visibilities vector
+/// averaged over timesteps (see `processing::VisSum`).
+///
+/// An (oversampled) FFT delay transform is done across the fine channels, with parabolic interpolation of the peak.
+///
+/// Fine channels do not need to be contiguous (e.g. picket fence observations) but must lie on a grid of
+/// `fine_chan_width_hz`.
+pub fn fit_delays(
+    averaged: &Array2<Jones<f32>>,
+    fine_chan_freqs_hz: &[f64],
+    fine_chan_width_hz: f64,
+) -> Vec<[DelayFit; 2]> {
+    // Place each fine channel on a regular grid
+    let grid_indices: Vec<usize> = fine_chan_freqs_hz
+        .iter()
+        .map(|f| ((f - fine_chan_freqs_hz[0]) / fine_chan_width_hz).round() as usize)
+        .collect();
+    let fft_len = (grid_indices.last().copied().unwrap_or_default() + 1) * DELAY_OVERSAMPLING;
+
+    let fft = FftPlanner::<f64>::new().plan_fft_forward(fft_len);
+
+    averaged
+        .axis_iter(Axis(1))
+        .into_par_iter()
+        .map(|bl_vis| {
+            [0, 3].map(|pol| {
+                fit_delay(
+                    bl_vis.map(|j| j[pol]).view(),
+                    &grid_indices,
+                    fine_chan_width_hz,
+                    &fft,
+                )
+            })
+        })
+        .collect()
+}
+
+/// Fits a delay to one baseline/polarisation's visibilities (one per fine channel)
+fn fit_delay(
+    vis: ArrayView1<Complex<f32>>,
+    grid_indices: &[usize],
+    fine_chan_width_hz: f64,
+    fft: &Arc<dyn Fft<f64>>,
+) -> DelayFit {
+    let fft_len = fft.len();
+
+    let mut buffer = vec![Complex::<f64>::new(0.0, 0.0); fft_len];
+    let mut sum_amp: f64 = 0.0;
+
+    for (v, grid_index) in vis.iter().zip(grid_indices) {
+        let v = Complex::new(v.re as f64, v.im as f64);
+        if v.re.is_finite() && v.im.is_finite() {
+            buffer[*grid_index] = v;
+            sum_amp += v.norm();
+        }
+    }
+
+    if sum_amp == 0.0 {
+        return DelayFit {
+            delay_ns: 0.0,
+            quality: 0.0,
+        };
+    }
+
+    fft.process(&mut buffer);
+
+    let amps: Vec<f64> = buffer.iter().map(|c| c.norm()).collect();
+
+    let (peak_index, peak_amp) = amps.iter().enumerate().fold(
+        (0, 0.0),
+        |(max_i, max_a), (i, a)| {
+            if *a > max_a {
+                (i, *a)
+            } else {
+                (max_i, max_a)
+            }
+        },
+    );
+
+    // Parabolic interpolation of the peak
+    let before = amps[(peak_index + fft_len - 1) % fft_len];
+    let after = amps[(peak_index + 1) % fft_len];
+    let denominator = before - 2.0 * peak_amp + after;
+    let offset = if denominator != 0.0 {
+        0.5 * (before - after) / denominator
+    } else {
+        0.0
+    };
+
+    // Indices in the upper half of the FFT are negative delays
+    let mut delay_bin = peak_index as f64 + offset;
+    if delay_bin > fft_len as f64 / 2.0 {
+        delay_bin -= fft_len as f64;
+    }
+
+    DelayFit {
+        delay_ns: (delay_bin / (fft_len as f64 * fine_chan_width_hz) * 1e9) as f32,
+        quality: (peak_amp / sum_amp) as f32,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fit_delays() {
+        let num_fine_chans = 128;
+        let fine_chan_width_hz = 10_000.0;
+        let fine_chan_freqs_hz: Vec<f64> = (0..num_fine_chans)
+            .map(|c| 150_000_000.0 + c as f64 * fine_chan_width_hz)
+            .collect();
+
+        // Baseline 0 has a delay of 1234 ns in XX and -500ns in YY, baseline 1 is noise-free but all zeros
+        let xx_delay_s = 1234e-9;
+        let yy_delay_s = -500e-9;
+        let mut averaged = Array2::<Jones<f32>>::zeros((num_fine_chans, 2));
+        for ((fine_chan, bl), j) in averaged.indexed_iter_mut() {
+            if bl == 0 {
+                let freq = fine_chan_freqs_hz[fine_chan];
+                let xx_phase = 2.0 * std::f64::consts::PI * freq * xx_delay_s;
+                let yy_phase = 2.0 * std::f64::consts::PI * freq * yy_delay_s;
+                *j = Jones::from([
+                    Complex::new(xx_phase.cos() as f32, xx_phase.sin() as f32),
+                    Complex::new(0.0, 0.0),
+                    Complex::new(0.0, 0.0),
+                    Complex::new(yy_phase.cos() as f32, yy_phase.sin() as f32),
+                ]);
+            }
+        }
+
+        let delays = fit_delays(&averaged, &fine_chan_freqs_hz, fine_chan_width_hz);
+
+        assert_eq!(delays.len(), 2);
+        assert!((delays[0][0].delay_ns - 1234.0).abs() < 20.0);
+        assert!(delays[0][0].quality > 0.9);
+        assert!((delays[0][1].delay_ns - -500.0).abs() < 20.0);
+        assert!(delays[0][1].quality > 0.9);
+        assert_eq!(delays[1][0].quality, 0.0);
+    }
+}
@@ -1,8 +1,201 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use birli::BirliError;
+use mwalib::{fitsio::errors::Error as FitsError, MwalibError};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+};
+
+/// Every way mwax_stats can fail. Each variant maps to a process exit code (see `exit_code`) so callers such as
+/// mwax_mover can tell the failures apart.
 #[derive(Debug)]
 pub enum MwaxStatsError {
+    /// A command line argument was not valid
+    InvalidArgument(String),
+    /// The metafits file could not be opened or understood
+    OpenMetafits(MwalibError),
+    /// The gpubox files could not be opened or understood
+    OpenGpuboxFiles(MwalibError),
+    /// No common good timesteps/coarse channels were found
     NoCommonGoodTimestepCCFound,
+    /// No common timesteps/coarse channels were found
     NoCommonTimestepCCFound,
+    /// No timesteps/coarse channels were provided at all
+    NoProvidedTimestepCCFound,
+    /// An explicitly selected timestep index was not provided
+    TimestepNotProvided(usize),
+    /// An explicitly selected coarse channel index was not provided
+    CoarseChanNotProvided(usize),
+    /// No timestep of the observation contains this GPS time (s)
+    NoTimestepAtGpsTime(f64),
+    /// Not even one timestep of the selected coarse channels fits within the memory limit
+    MemoryLimitTooSmall {
+        memory_limit_gb: f32,
+        required_gb: f32,
+    },
+    /// Visibilities could not be read from the gpubox files
+    ReadData(BirliError),
+    /// Birli failed to select, flag or correct the data
+    Preprocess(BirliError),
+    /// A calibration solutions file could not be read
+    ReadCalibrationSolutions { filename: PathBuf, reason: String },
+    /// The packet stats could not be read from a subfile (mwax_packet_stats)
+    ReadSubfile { filename: PathBuf, reason: String },
+    /// An output file could not be written
+    WriteFile {
+        filename: PathBuf,
+        source: io::Error,
+    },
+    /// An output FITS file could not be written
+    WriteFits {
+        filename: PathBuf,
+        source: FitsError,
+    },
+}
+
+impl MwaxStatsError {
+    /// Convenience for `map_err` when writing `filename` fails
+    pub fn write_file(filename: &Path, source: io::Error) -> Self {
+        MwaxStatsError::WriteFile {
+            filename: filename.to_path_buf(),
+            source,
+        }
+    }
+
+    /// The process exit code for this error:
+    ///
+    /// * 2 - invalid command line argument
+    /// * 3 - the metafits file could not be opened or read
+    /// * 4 - the gpubox files could not be opened or read (e.g. corrupt FITS)
+    /// * 5 - birli preprocessing (selection, flagging or corrections) failed
+    /// * 6 - no good data: no timesteps/coarse channels could be selected
+    /// * 7 - an output file could not be written (e.g. disk full)
+    /// * 8 - the calibration solutions could not be read
+    /// * 9 - the memory limit is too small to process even one timestep
+    /// * 10 - the packet stats could not be read from the subfile
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            MwaxStatsError::InvalidArgument(_) => 2,
+            MwaxStatsError::OpenMetafits(_) => 3,
+            MwaxStatsError::OpenGpuboxFiles(_) => 4,
+            MwaxStatsError::ReadData(_) => 4,
+            MwaxStatsError::Preprocess(_) => 5,
+            MwaxStatsError::NoCommonGoodTimestepCCFound => 6,
+            MwaxStatsError::NoCommonTimestepCCFound => 6,
+            MwaxStatsError::NoProvidedTimestepCCFound => 6,
+            MwaxStatsError::TimestepNotProvided(_) => 6,
+            MwaxStatsError::CoarseChanNotProvided(_) => 6,
+            MwaxStatsError::NoTimestepAtGpsTime(_) => 6,
+            MwaxStatsError::WriteFile { .. } => 7,
+            MwaxStatsError::WriteFits { .. } => 7,
+            MwaxStatsError::ReadCalibrationSolutions { .. } => 8,
+            MwaxStatsError::MemoryLimitTooSmall { .. } => 9,
+            MwaxStatsError::ReadSubfile { .. } => 10,
+        }
+    }
+}
+
+impl fmt::Display for MwaxStatsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MwaxStatsError::InvalidArgument(reason) => write!(f, "Invalid argument: {}", reason),
+            MwaxStatsError::OpenMetafits(e) => write!(f, "Unable to open metafits: {}", e),
+            MwaxStatsError::OpenGpuboxFiles(e) => write!(f, "Unable to open gpubox files: {}", e),
+            MwaxStatsError::NoCommonGoodTimestepCCFound => write!(
+                f,
+                "No common good timesteps/coarse channels found (use -t to use any common timestep)"
+            ),
+            MwaxStatsError::NoCommonTimestepCCFound => {
+                write!(f, "No common timesteps/coarse channels found")
+            }
+            MwaxStatsError::NoProvidedTimestepCCFound => {
+                write!(f, "No timesteps/coarse channels were provided")
+            }
+            MwaxStatsError::TimestepNotProvided(t) => {
+                write!(f, "No data was provided for timestep index {}", t)
+            }
+            MwaxStatsError::CoarseChanNotProvided(c) => {
+                write!(f, "No data was provided for coarse channel index {}", c)
+            }
+            MwaxStatsError::NoTimestepAtGpsTime(gps_time) => {
+                write!(f, "No timestep contains GPS time {}", gps_time)
+            }
+            MwaxStatsError::MemoryLimitTooSmall {
+                memory_limit_gb,
+                required_gb,
+            } => write!(
+                f,
+                "Memory limit of {} GB is too small, at least {} GB is needed to process one timestep",
+                memory_limit_gb, required_gb
+            ),
+            MwaxStatsError::ReadData(e) => write!(f, "Unable to read visibilities: {}", e),
+            MwaxStatsError::Preprocess(e) => write!(f, "Unable to preprocess data: {}", e),
+            MwaxStatsError::ReadCalibrationSolutions { filename, reason } => write!(
+                f,
+                "Unable to read calibration solutions {}: {}",
+                filename.display(),
+                reason
+            ),
+            MwaxStatsError::ReadSubfile { filename, reason } => write!(
+                f,
+                "Unable to read the packet stats of subfile {}: {}",
+                filename.display(),
+                reason
+            ),
+            MwaxStatsError::WriteFile { filename, source } => {
+                write!(f, "Unable to write {}: {}", filename.display(), source)
+            }
+            MwaxStatsError::WriteFits { filename, source } => {
+                write!(f, "Unable to write {}: {}", filename.display(), source)
+            }
+        }
+    }
+}
+
+impl std::error::Error for MwaxStatsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MwaxStatsError::OpenMetafits(e) => Some(e),
+            MwaxStatsError::OpenGpuboxFiles(e) => Some(e),
+            MwaxStatsError::ReadData(e) => Some(e),
+            MwaxStatsError::Preprocess(e) => Some(e),
+            MwaxStatsError::WriteFile { source, .. } => Some(source),
+            MwaxStatsError::WriteFits { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_codes_are_distinct() {
+        let write_error = MwaxStatsError::WriteFile {
+            filename: PathBuf::from("/tmp/x.dat"),
+            source: io::Error::other("disk full"),
+        };
+
+        assert_eq!(MwaxStatsError::NoCommonGoodTimestepCCFound.exit_code(), 6);
+        assert_eq!(write_error.exit_code(), 7);
+        assert_eq!(
+            MwaxStatsError::ReadSubfile {
+                filename: PathBuf::from("/tmp/x.sub"),
+                reason: "no header".to_string(),
+            }
+            .exit_code(),
+            10
+        );
+        assert_ne!(
+            MwaxStatsError::InvalidArgument("x".to_string()).exit_code(),
+            MwaxStatsError::NoCommonTimestepCCFound.exit_code()
+        );
+        assert_eq!(
+            write_error.to_string(),
+            "Unable to write /tmp/x.dat: disk full"
+        );
+    }
 }
@@ -1,152 +1,354 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
-use crate::processing;
-use log::{debug, info, trace};
+use crate::errors::MwaxStatsError;
+use crate::header::{FileHeader, ObsInfo, OutputFormat, RecordLayout};
+use crate::processing::{Corrections, VisSum};
+use core::ops::Range;
+use log::{debug, info, trace, warn};
 use mwalib::CorrelatorContext;
+use ndarray::{Array2, Array3};
 use std::fs::File;
-use std::io::{BufWriter, Write};
-use std::path::Path;
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
 
-/// Outputs one binary file for an observation.
+/// The layout of each record in the fringes file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FringeRecordVersion {
+    /// [fine chan freq][XX phase][YY phase]
+    V1,
+    /// [fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps]
+    V2,
+    /// [fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps][XY phase][YX phase][XY amp][YX amp]
+    V3,
+}
+
+impl FringeRecordVersion {
+    /// The `RecordLayout` in the file header
+    fn record_layout(self) -> RecordLayout {
+        match self {
+            FringeRecordVersion::V1 => RecordLayout::FringesV1,
+            FringeRecordVersion::V2 => RecordLayout::FringesV2,
+            FringeRecordVersion::V3 => RecordLayout::FringesV3,
+        }
+    }
+}
+
+/// The fringes of one coarse channel: the weighted vector average over the unflagged timesteps of the corrected data of
+/// every baseline and fine channel. Baselines and fine channels which are flagged for every timestep (e.g. flagged
+/// tiles) have NaN phases and amplitudes.
+#[derive(Clone, Debug)]
+pub struct FringeSet {
+    pub coarse_chan_index: usize,
+    /// The timesteps which contributed to the sums
+    pub timestep_indices: Vec<usize>,
+    pub corrections: Corrections,
+    pub freqs_mhz: Vec<f32>,
+    /// [baseline][fine chan][pol] (deg), in the Birli Jones Matrix pol order: XX, XY, YX, YY
+    pub phase_deg: Array3<f32>,
+    /// [baseline][fine chan][pol], in the same pol order as `phase_deg`
+    pub amp: Array3<f32>,
+    /// The number of unflagged timesteps in the average of each [baseline][fine chan]
+    pub num_timesteps: Array2<u32>,
+}
+
+impl FringeSet {
+    /// The fringes of the fine channels in `vis_sum` starting at `sum_fine_chan_offset` (one per frequency in
+    /// `freqs_mhz`).
+    pub fn from_vis_sum(
+        vis_sum: &VisSum,
+        sum_fine_chan_offset: usize,
+        coarse_chan_index: usize,
+        timestep_indices: Vec<usize>,
+        corrections: Corrections,
+        freqs_mhz: Vec<f32>,
+    ) -> Self {
+        let num_baselines = vis_sum.sum().dim().1;
+        let shape = (num_baselines, freqs_mhz.len(), 4);
+        let mut phase_deg = Array3::<f32>::from_elem(shape, f32::NAN);
+        let mut amp = Array3::<f32>::from_elem(shape, f32::NAN);
+        let mut num_timesteps = Array2::<u32>::zeros((num_baselines, freqs_mhz.len()));
+
+        for bl_index in 0..num_baselines {
+            for fine_chan_index in 0..freqs_mhz.len() {
+                let index = [sum_fine_chan_offset + fine_chan_index, bl_index];
+                let data = vis_sum.sum()[index];
+                let weight_sum = vis_sum.weight_sum()[index];
+                num_timesteps[[bl_index, fine_chan_index]] = vis_sum.num_timesteps()[index];
+
+                // Phase (deg) and amplitude of the weighted vector average of each pol
+                if weight_sum > 0.0 {
+                    for pol in 0..4 {
+                        phase_deg[[bl_index, fine_chan_index, pol]] =
+                            data[pol].arg().to_degrees() as f32;
+                        amp[[bl_index, fine_chan_index, pol]] =
+                            (data[pol].norm() / weight_sum) as f32;
+                    }
+                }
+            }
+        }
+
+        FringeSet {
+            coarse_chan_index,
+            timestep_indices,
+            corrections,
+            freqs_mhz,
+            phase_deg,
+            amp,
+            num_timesteps,
+        }
+    }
+
+    /// The values of the record of a baseline and fine channel, as written to the files (see `FringesFileWriter`)
+    pub fn record(
+        &self,
+        bl_index: usize,
+        fine_chan_index: usize,
+        record_version: FringeRecordVersion,
+    ) -> Vec<f32> {
+        // The Birli Jones Matrix pols are in order: xx, xy, yx, yy
+        let phase_deg = |pol: usize| self.phase_deg[[bl_index, fine_chan_index, pol]];
+        let amp = |pol: usize| self.amp[[bl_index, fine_chan_index, pol]];
+        let fine_chan_freq_mhz = self.freqs_mhz[fine_chan_index];
+        let num_timesteps = self.num_timesteps[[bl_index, fine_chan_index]] as f32;
+
+        match record_version {
+            FringeRecordVersion::V1 => vec![fine_chan_freq_mhz, phase_deg(0), phase_deg(3)],
+            FringeRecordVersion::V2 => vec![
+                fine_chan_freq_mhz,
+                phase_deg(0),
+                phase_deg(3),
+                amp(0),
+                amp(3),
+                num_timesteps,
+            ],
+            FringeRecordVersion::V3 => vec![
+                fine_chan_freq_mhz,
+                phase_deg(0),
+                phase_deg(3),
+                amp(0),
+                amp(3),
+                num_timesteps,
+                phase_deg(1),
+                phase_deg(2),
+                amp(1),
+                amp(2),
+            ],
+        }
+    }
+}
+
+/// Somewhere to put the `FringeSet` of each coarse channel, so `output_fringes` does not need to know the output
+/// format.
+pub trait FringesWriter {
+    fn write_fringes(&mut self, fringes: &FringeSet) -> Result<(), MwaxStatsError>;
+}
+
+/// The fringes of the coarse channel `coarse_chan_index`, from the corrected data of the coarse channels in
+/// `coarse_chan_range` summed over the timesteps `timestep_indices` in `vis_sum`.
+pub fn get_fringe_set(
+    context: &CorrelatorContext,
+    vis_sum: &VisSum,
+    timestep_indices: &[usize],
+    coarse_chan_range: &Range<usize>,
+    coarse_chan_index: usize,
+    corrections: &Corrections,
+) -> FringeSet {
+    let fine_chans_per_coarse = context.metafits_context.num_corr_fine_chans_per_coarse;
+
+    // Establish the starting index for the fine channel frequency array. It is for all channels whether we provided data or not
+    let fine_chan_freq_index = coarse_chan_index * fine_chans_per_coarse;
+    let freqs_mhz: Vec<f32> = context.metafits_context.metafits_fine_chan_freqs_hz
+        [fine_chan_freq_index..fine_chan_freq_index + fine_chans_per_coarse]
+        .iter()
+        .map(|f| (f / 1000000.0) as f32)
+        .collect();
+
+    // The sums contain the fine channels of every coarse channel in the range
+    FringeSet::from_vis_sum(
+        vis_sum,
+        (coarse_chan_index - coarse_chan_range.start) * fine_chans_per_coarse,
+        coarse_chan_index,
+        timestep_indices.to_vec(),
+        *corrections,
+        freqs_mhz,
+    )
+}
+
+/// Works out the fringes (see `get_fringe_set`) of each coarse channel in `coarse_chan_range` which we have data for,
+/// and passes them to `writer`. As only the sums are needed the data can be read one chunk of timesteps at a time.
+/// `timestep_indices` are the timesteps which contributed to `vis_sum` (see `stats::CalibratorSums`).
+pub fn output_fringes(
+    context: &CorrelatorContext,
+    writer: &mut dyn FringesWriter,
+    vis_sum: &VisSum,
+    timestep_indices: &[usize],
+    coarse_chan_range: &Range<usize>,
+    corrections: &Corrections,
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_fringes()...");
+
+    if timestep_indices.is_empty() {
+        warn!(
+            "Skipping output_fringes() as none of the selected timesteps have any unflagged data."
+        );
+        return Ok(());
+    }
+
+    // One set of fringes per coarse channel
+    for coarse_chan_index in coarse_chan_range.clone() {
+        // The range may span coarse channels we were not given files for
+        if !context
+            .provided_coarse_chan_indices
+            .contains(&coarse_chan_index)
+        {
+            debug!(
+                "Skipping coarse channel index: {} as no data was provided for it",
+                coarse_chan_index
+            );
+            continue;
+        }
+
+        let fringes = get_fringe_set(
+            context,
+            vis_sum,
+            timestep_indices,
+            coarse_chan_range,
+            coarse_chan_index,
+            corrections,
+        );
+
+        writer.write_fringes(&fringes)?;
+    }
+
+    Ok(())
+}
+
+/// Writes the fringes as one binary file per coarse channel.
 ///
-/// Each file is named OBSID_fringes_NFINECHANSchans_128T.dat (128 is the number of tiles which may vary)
+/// Each file is named OBSID_fringes_NFINECHANSchans_128T_chRECCHAN.dat (128 is the number of tiles which may vary)
+/// for `FringeRecordVersion::V1`, or OBSID_fringes_vN_NFINECHANSchans_128T_chRECCHAN.dat for `FringeRecordVersion::V2`
+/// and later (where N is the version number).
 ///
-/// File format 3 floats * num fine channels per coarse * coarse channels passed in * tiles:
+/// V1 file format 3 floats * num fine channels per coarse * baselines:
 /// Slowest moving -> fastest moving
 /// [ant1][ant2][fine chan freq][XX phase][YY phase]
 ///
 ///     fine chan freq (MHz)
 ///     phase(XX) (deg)
 ///     phase(YY) (deg)
-pub fn output_fringes(
-    context: &CorrelatorContext,
-    output_dir: &str,
-    use_any_timestep: bool,
-    max_memory_gb: Option<f32>,
-    correct_cable_lengths: bool,
-    correct_digital_gains: bool,
-    correct_passband_gains: bool,
-    correct_geometry: bool,
-) {
-    info!("Starting output_fringes()...");
+///
+/// V2 file format 6 floats * num fine channels per coarse * baselines:
+/// Slowest moving -> fastest moving
+/// [ant1][ant2][fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps]
+///
+///     fine chan freq (MHz)
+///     phase(XX) (deg)
+///     phase(YY) (deg)
+///     amplitude(XX) (amplitude of the vector average over timesteps)
+///     amplitude(YY) (amplitude of the vector average over timesteps)
+///     number of (unflagged) timesteps contributing to the vector average
+///
+/// V3 file format 10 floats * num fine channels per coarse * baselines. This is V2 with the cross polarisations appended:
+/// [ant1][ant2][fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps][XY phase][YX phase][XY amp][YX amp]
+///
+///     phase(XY) (deg)
+///     phase(YX) (deg)
+///     amplitude(XY) (amplitude of the vector average over timesteps)
+///     amplitude(YX) (amplitude of the vector average over timesteps)
+///
+/// With `OutputFormat::Header` every file starts with a `FileHeader` describing it, including the timesteps summed and
+/// the corrections applied.
+pub struct FringesFileWriter {
+    obs: ObsInfo,
+    output_dir: PathBuf,
+    record_version: FringeRecordVersion,
+    output_format: OutputFormat,
+}
+
+impl FringesFileWriter {
+    pub fn new(
+        obs: ObsInfo,
+        output_dir: &str,
+        record_version: FringeRecordVersion,
+        output_format: OutputFormat,
+    ) -> Self {
+        FringesFileWriter {
+            obs,
+            output_dir: PathBuf::from(output_dir),
+            record_version,
+            output_format,
+        }
+    }
+}
 
-    // Determine timestep and coarse channel range
-    // For fringes we only want all the common good timesteps if possible; and one coarse channel
-    let (timestep_range, coarse_chan_range) =
-        processing::get_timesteps_coarse_chan_ranges(context, use_any_timestep, max_memory_gb)
-            .unwrap();
-
-    // Output the timestep and coarse channel ranges and debug
-    debug!(
-        "Timesteps   : {} indicies: {}..{}",
-        timestep_range.len(),
-        timestep_range.start,
-        timestep_range.end - 1
-    );
-    debug!(
-        "Coarse chans: {} indicies: {}..{}",
-        coarse_chan_range.len(),
-        coarse_chan_range.start,
-        coarse_chan_range.end - 1
-    );
-
-    // Get data
-    let jones_array = processing::get_corrected_data(
-        context,
-        &timestep_range,
-        &coarse_chan_range,
-        correct_cable_lengths,
-        correct_digital_gains,
-        correct_passband_gains,
-        correct_geometry,
-    );
-
-    // Open a file for writing
-    let output_filename = Path::new(output_dir).join(format!(
-        "{}_fringes_{}chans_{}T_ch{}.dat",
-        context.metafits_context.obs_id,
-        context.metafits_context.num_corr_fine_chans_per_coarse,
-        context.metafits_context.num_ants,
-        context.coarse_chans[coarse_chan_range.start].rec_chan_number
-    ));
+impl FringesWriter for FringesFileWriter {
+    fn write_fringes(&mut self, fringes: &FringeSet) -> Result<(), MwaxStatsError> {
+        let obs = &self.obs;
+        // Open a file for writing
+        let output_filename = self.output_dir.join(format!(
+            "{}_fringes_{}{}chans_{}T_ch{}.dat",
+            obs.obs_id,
+            match self.record_version {
+                FringeRecordVersion::V1 => "",
+                FringeRecordVersion::V2 => "v2_",
+                FringeRecordVersion::V3 => "v3_",
+            },
+            obs.num_fine_chans,
+            obs.num_ants,
+            obs.rec_chan_numbers[fringes.coarse_chan_index]
+        ));
 
-    // Establish the starting index for the fine channel frequency array. It is for all channels whether we provided data or not
-    let fine_chan_freq_index =
-        coarse_chan_range.start * context.metafits_context.num_corr_fine_chans_per_coarse;
+        let header = (self.output_format == OutputFormat::Header)
+            .then(|| {
+                FileHeader::new(
+                    obs,
+                    fringes.coarse_chan_index,
+                    &fringes.timestep_indices,
+                    self.record_version.record_layout(),
+                    &fringes.corrections,
+                )
+            })
+            .transpose()?;
 
-    // Create output file for writing
-    let output_file =
-        File::create(&output_filename).expect("Unable to open fringe file for writing");
+        // Create output file for writing
+        let output_file = File::create(&output_filename)
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
 
-    let mut writer = BufWriter::new(&output_file);
+        let mut writer = BufWriter::new(&output_file);
 
-    // Loop through all of the baselines
-    for (bl_index, bl) in context.metafits_context.baselines.iter().enumerate() {
-        // Loop through fine channels
-        for fine_chan_index in 0..context.metafits_context.num_corr_fine_chans_per_coarse {
-            let mut xx_r: f64 = 0.0;
-            let mut xx_i: f64 = 0.0;
-            let mut yy_r: f64 = 0.0;
-            let mut yy_i: f64 = 0.0;
-
-            // Determine fine chan frequency
-            let fine_chan_freq_mhz = (&context.metafits_context.metafits_fine_chan_freqs_hz
-                [fine_chan_freq_index + fine_chan_index]
-                / 1000000.0) as f32;
-
-            for timestep_loop_index in 0..timestep_range.len() {
-                // The Birli Jones Matrix is in order:
-                // timestep, fine_chan, baseline and then pol
-                let data = jones_array[[timestep_loop_index, fine_chan_index, bl_index]];
-
-                // Calculate Phase of XX and YY
-                // data for each fine channel is: xx_r, xx_i, xy_r, xy_i, yx_r, yx_i, yy_r, yy_i
-                xx_r += data[0].re as f64;
-                xx_i += data[0].im as f64;
-                yy_r += data[3].re as f64;
-                yy_i += data[3].im as f64;
-            }
+        header
+            .map_or(Ok(()), |header| header.write(&mut writer))
+            .and_then(|_| write_fringes_records(&mut writer, fringes, self.record_version))
+            .and_then(|_| writer.flush())
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
 
-            let xx_phase_deg: f32 = xx_i.atan2(xx_r).to_degrees() as f32;
-            let yy_phase_deg: f32 = yy_i.atan2(yy_r).to_degrees() as f32;
-
-            if bl_index == 1 {
-                trace!(
-                    "{},{},{},{},{},{},{},{},{},{},{}",
-                    bl.ant1_index,
-                    bl.ant2_index,
-                    fine_chan_freq_index + fine_chan_index,
-                    fine_chan_index,
-                    fine_chan_freq_mhz,
-                    xx_phase_deg,
-                    yy_phase_deg,
-                    xx_r,
-                    xx_i,
-                    yy_r,
-                    yy_i
-                );
-            }
+        info!("Done! {} written.", output_filename.display());
 
-            let float_vec = vec![fine_chan_freq_mhz, xx_phase_deg, yy_phase_deg];
+        Ok(())
+    }
+}
 
-            let float_bytes: Vec<u8> = floats_to_bytes(float_vec);
-            // Write data to file
-            writer
-                .write_all(&float_bytes)
-                .expect("Error writing fringe data");
+/// Writes the fringes records (see `FringesFileWriter`) of every baseline and fine channel in `fringes`.
+pub(crate) fn write_fringes_records<W: Write>(
+    writer: &mut W,
+    fringes: &FringeSet,
+    record_version: FringeRecordVersion,
+) -> io::Result<()> {
+    // Loop through all of the baselines
+    for (bl_index, fine_chan_index) in fringes.num_timesteps.indexed_iter().map(|(index, _)| index)
+    {
+        let float_vec = fringes.record(bl_index, fine_chan_index, record_version);
+
+        if bl_index == 1 {
+            trace!("{},{},{:?}", bl_index, fine_chan_index, float_vec);
         }
-    }
 
-    writer.flush().expect("Error flushing output file to disk");
+        // Write data to file
+        writer.write_all(&floats_to_bytes(float_vec))?;
+    }
 
-    info!(
-        "Done! {} written.",
-        &output_filename
-            .into_os_string()
-            .to_str()
-            .expect("Could not convert path into string")
-    );
+    Ok(())
 }
 
 pub fn floats_to_bytes(floats: Vec<f32>) -> Vec<u8> {
@@ -157,3 +359,324 @@ pub fn floats_to_bytes(floats: Vec<f32>) -> Vec<u8> {
     }
     byte_array
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::CorrectedData;
+    use crate::rfi;
+    use birli::{marlu::Complex, Jones};
+    use ndarray::{s, Array3};
+
+    #[test]
+    fn test_write_fringes_chunked() {
+        let num_timesteps = 7;
+        let num_fine_chans = 4;
+        let num_baselines = 3;
+        let freqs_mhz: Vec<f32> = (0..num_fine_chans)
+            .map(|c| 150.0 + c as f32 * 0.01)
+            .collect();
+
+        // Visibilities with a different phase and amplitude for every timestep, fine channel, baseline and pol
+        let shape = (num_timesteps, num_fine_chans, num_baselines);
+        let mut data = CorrectedData {
+            jones: Array3::<Jones<f32>>::zeros(shape),
+            weights: Array3::<f32>::ones(shape),
+        };
+        for ((timestep, fine_chan, bl), j) in data.jones.indexed_iter_mut() {
+            let x = (timestep * 31 + fine_chan * 7 + bl * 3) as f32;
+            *j = Jones::from([
+                Complex::from_polar(1.0 + x.sin().abs(), 0.1 * x),
+                Complex::from_polar(0.5, -0.2 * x),
+                Complex::from_polar(0.25 + x.cos().abs(), 0.3 * x),
+                Complex::from_polar(2.0, x.sqrt()),
+            ]);
+        }
+
+        // Baseline 1 fine chan 1 has a flagged timestep and fine chan 2 is fully flagged
+        data.weights[[3, 1, 1]] = 0.0;
+        data.weights.slice_mut(s![.., 2, 1]).fill(0.0);
+
+        let fringes = |chunk_size: usize| {
+            let mut vis_sum = VisSum::new(num_fine_chans, num_baselines);
+            for start in (0..num_timesteps).step_by(chunk_size) {
+                let end = (start + chunk_size).min(num_timesteps);
+                vis_sum.add(&CorrectedData {
+                    jones: data.jones.slice(s![start..end, .., ..]).to_owned(),
+                    weights: data.weights.slice(s![start..end, .., ..]).to_owned(),
+                });
+            }
+
+            FringeSet::from_vis_sum(
+                &vis_sum,
+                0,
+                0,
+                (0..num_timesteps).collect(),
+                Corrections::default(),
+                freqs_mhz.clone(),
+            )
+        };
+        let records = |fringes: &FringeSet| {
+            let mut bytes = Vec::new();
+            write_fringes_records(&mut bytes, fringes, FringeRecordVersion::V3).unwrap();
+            bytes
+        };
+
+        // The science, without writing anything
+        let fringe_set = fringes(num_timesteps);
+        assert_eq!(
+            fringe_set.phase_deg.dim(),
+            (num_baselines, num_fine_chans, 4)
+        );
+        assert_eq!(fringe_set.num_timesteps[[1, 1]], (num_timesteps - 1) as u32);
+        assert!(fringe_set.amp[[1, 2, 0]].is_nan());
+
+        let unchunked = records(&fringe_set);
+        assert_eq!(unchunked.len(), num_baselines * num_fine_chans * 10 * 4);
+
+        // Chunked runs produce exactly the same output as the in-memory one
+        for chunk_size in [1, 2, 3] {
+            assert_eq!(records(&fringes(chunk_size)), unchunked);
+        }
+
+        let record = |bl: usize, fine_chan: usize| -> Vec<f32> {
+            let start = (bl * num_fine_chans + fine_chan) * 10 * 4;
+            unchunked[start..start + 10 * 4]
+                .chunks(4)
+                .map(|b| f32::from_le_bytes(b.try_into().unwrap()))
+                .collect()
+        };
+
+        // The number of unflagged timesteps is recorded
+        assert_eq!(record(0, 0)[5], num_timesteps as f32);
+        assert_eq!(record(1, 1)[5], (num_timesteps - 1) as f32);
+
+        // Fully flagged cells are NaN
+        let flagged = record(1, 2);
+        assert_eq!(flagged[5], 0.0);
+        assert!(flagged[1..5]
+            .iter()
+            .chain(&flagged[6..])
+            .all(|f| f.is_nan()));
+
+        // The flagged timestep is not included in the average
+        let expected_yy = (0..num_timesteps)
+            .filter(|timestep| *timestep != 3)
+            .map(|timestep| {
+                let yy = data.jones[[timestep, 1, 1]][3];
+                Complex::new(yy.re as f64, yy.im as f64)
+            })
+            .sum::<Complex<f64>>()
+            / (num_timesteps - 1) as f64;
+        assert!((record(1, 1)[4] as f64 - expected_yy.norm()).abs() < 1e-5);
+    }
+
+    #[test]
+    fn test_write_fringes_chunked_rfi_flagging() {
+        let (num_timesteps, num_fine_chans, num_baselines) = (7, 32, 3);
+        let freqs_mhz: Vec<f32> = (0..num_fine_chans)
+            .map(|c| 150.0 + c as f32 * 0.01)
+            .collect();
+
+        // Noise around an amplitude of 10, from a simple LCG
+        let mut seed: u32 = 54321;
+        let mut noise = || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        };
+        let shape = (num_timesteps, num_fine_chans, num_baselines);
+        let mut data = CorrectedData {
+            jones: Array3::<Jones<f32>>::zeros(shape),
+            weights: Array3::<f32>::ones(shape),
+        };
+        for j in data.jones.iter_mut() {
+            *j = Jones::from([
+                Complex::new(10.0 + noise(), noise()),
+                Complex::new(noise(), noise()),
+                Complex::new(noise(), noise()),
+                Complex::new(10.0 + noise(), noise()),
+            ]);
+        }
+
+        // RFI in fine channel 5 of baseline 1 for some of the timesteps, and a broadband burst in timestep 3
+        for timestep in 2..5 {
+            data.jones[[timestep, 5, 1]][0] += Complex::new(20.0, 0.0);
+        }
+        for fine_chan in 16..24 {
+            data.jones[[3, fine_chan, 2]][3] += Complex::new(1.5, 0.0);
+        }
+
+        // Flags each chunk as it is summed, like `stats::get_calibrator_sums`
+        let fringes = |chunk_size: usize| {
+            let mut vis_sum = VisSum::new(num_fine_chans, num_baselines);
+            let mut rfi_flags = Vec::new();
+            for start in (0..num_timesteps).step_by(chunk_size) {
+                let end = (start + chunk_size).min(num_timesteps);
+                let mut chunk = CorrectedData {
+                    jones: data.jones.slice(s![start..end, .., ..]).to_owned(),
+                    weights: data.weights.slice(s![start..end, .., ..]).to_owned(),
+                };
+                rfi_flags.extend(rfi::flag_rfi(&mut chunk, num_fine_chans));
+                vis_sum.add(&chunk);
+            }
+
+            let fringe_set = FringeSet::from_vis_sum(
+                &vis_sum,
+                0,
+                0,
+                (0..num_timesteps).collect(),
+                Corrections::default(),
+                freqs_mhz.clone(),
+            );
+            let mut records = Vec::new();
+            write_fringes_records(&mut records, &fringe_set, FringeRecordVersion::V3).unwrap();
+            (rfi_flags, records)
+        };
+
+        let (unchunked_rfi_flags, unchunked) = fringes(num_timesteps);
+        let rfi_flag = |timestep: usize, fine_chan: usize, bl: usize| {
+            unchunked_rfi_flags[(timestep * num_fine_chans + fine_chan) * num_baselines + bl]
+        };
+        assert!((2..5).all(|timestep| rfi_flag(timestep, 5, 1)));
+        assert!(!rfi_flag(1, 5, 1));
+        assert!((16..24).all(|fine_chan| rfi_flag(3, fine_chan, 2)));
+
+        // Chunked runs flag the same visibilities, so produce exactly the same output
+        for chunk_size in [1, 2, 3] {
+            assert_eq!(
+                fringes(chunk_size),
+                (unchunked_rfi_flags.clone(), unchunked.clone())
+            );
+        }
+    }
+
+    #[test]
+    fn test_fringes_file_writer() {
+        use crate::reader::{FileName, FringesFile};
+
+        // 2 tiles, so 3 baselines
+        let (num_timesteps, num_fine_chans, num_baselines) = (3, 2, 3);
+        let freqs_mhz = vec![150.0, 150.01];
+
+        let shape = (num_timesteps, num_fine_chans, num_baselines);
+        let mut data = CorrectedData {
+            jones: Array3::<Jones<f32>>::zeros(shape),
+            weights: Array3::<f32>::ones(shape),
+        };
+        for ((timestep, fine_chan, bl), j) in data.jones.indexed_iter_mut() {
+            let x = (timestep * 5 + fine_chan * 3 + bl) as f32;
+            *j = Jones::from([
+                Complex::new(x.cos(), x.sin()),
+                Complex::new(0.0, 0.0),
+                Complex::new(0.0, 0.0),
+                Complex::new(1.0 + x, -x),
+            ]);
+        }
+        // The middle timestep is flagged, so contributes nothing
+        data.weights.slice_mut(s![1, .., ..]).fill(0.0);
+
+        let mut vis_sum = VisSum::new(num_fine_chans, num_baselines);
+        vis_sum.add(&data);
+        let timestep_indices: Vec<usize> = data
+            .unflagged_timesteps()
+            .into_iter()
+            .map(|timestep| 10 + timestep)
+            .collect();
+        let fringe_set = FringeSet::from_vis_sum(
+            &vis_sum,
+            0,
+            4,
+            timestep_indices,
+            Corrections::default(),
+            freqs_mhz.clone(),
+        );
+
+        let obs = ObsInfo {
+            obs_id: 1317706936,
+            num_ants: 2,
+            num_fine_chans,
+            corr_int_time_ms: 2000,
+            rec_chan_numbers: vec![100, 101, 102, 103, 104],
+            timestep_gps_times_ms: (0..16).map(|t| 1317706936000 + t * 2000).collect(),
+        };
+
+        // The only file written to a directory, read back
+        let write = |record_version: FringeRecordVersion, output_format: OutputFormat| {
+            let dir = tempfile::tempdir().unwrap();
+            let mut writer = FringesFileWriter::new(
+                obs.clone(),
+                dir.path().to_str().unwrap(),
+                record_version,
+                output_format,
+            );
+            writer.write_fringes(&fringe_set).unwrap();
+
+            let paths: Vec<_> = std::fs::read_dir(dir.path())
+                .unwrap()
+                .map(|entry| entry.unwrap().path())
+                .collect();
+            assert_eq!(paths.len(), 1);
+            let filename = paths[0].file_name().unwrap().to_str().unwrap().to_string();
+            (filename, FringesFile::read(&paths[0]).unwrap())
+        };
+
+        // Every value of each record (after the frequency) is the one from the fringe set
+        let assert_records = |fringes_file: &FringesFile, record_version: FringeRecordVersion| {
+            assert_eq!(fringes_file.freqs_mhz.to_vec(), freqs_mhz);
+            for bl in 0..num_baselines {
+                for fine_chan in 0..num_fine_chans {
+                    assert_eq!(
+                        fringes_file.values.slice(s![bl, fine_chan, ..]).to_vec(),
+                        fringe_set.record(bl, fine_chan, record_version)[1..]
+                    );
+                }
+            }
+        };
+
+        // Version 2 with a header
+        let (filename, fringes_file) = write(FringeRecordVersion::V2, OutputFormat::Header);
+        assert_eq!(filename, "1317706936_fringes_v2_2chans_2T_ch104.dat");
+        assert_eq!(
+            FileName::parse(&filename).unwrap().record_layout,
+            RecordLayout::FringesV2
+        );
+        let header = fringes_file.header.unwrap();
+        assert_eq!(header.record_layout, RecordLayout::FringesV2);
+        assert_eq!(header.rec_chan_number, 104);
+        // Timesteps 10 and 12
+        assert_eq!(header.num_timesteps, 2);
+        assert_eq!(header.start_gps_time_ms, 1317706956000);
+        assert_eq!(header.end_gps_time_ms, 1317706962000);
+        assert_records(&fringes_file, FringeRecordVersion::V2);
+
+        // The XX and YY phase summed over the unflagged timesteps
+        let phase_deg = |bl: usize, fine_chan: usize, pol: usize| {
+            [0, 2]
+                .iter()
+                .map(|timestep| {
+                    let c = data.jones[[*timestep, fine_chan, bl]][pol];
+                    Complex::new(c.re as f64, c.im as f64)
+                })
+                .sum::<Complex<f64>>()
+                .arg()
+                .to_degrees() as f32
+        };
+        assert_eq!(fringes_file.values[[2, 1, 0]], phase_deg(2, 1, 0));
+        assert_eq!(fringes_file.values[[2, 1, 1]], phase_deg(2, 1, 3));
+        assert_eq!(fringes_file.values[[2, 1, 4]], 2.0);
+
+        // Version 1 as a legacy file
+        let (filename, fringes_file) = write(FringeRecordVersion::V1, OutputFormat::Legacy);
+        assert_eq!(filename, "1317706936_fringes_2chans_2T_ch104.dat");
+        assert_eq!(
+            FileName::parse(&filename).unwrap().record_layout,
+            RecordLayout::FringesV1
+        );
+        assert!(fringes_file.header.is_none());
+        assert_eq!(
+            fringes_file.values.dim(),
+            (num_baselines, num_fine_chans, 2)
+        );
+        assert_records(&fringes_file, FringeRecordVersion::V1);
+    }
+}
@@ -0,0 +1,296 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use birli::{marlu::Complex, Jones};
+use core::ops::Range;
+use log::{debug, info, trace};
+use mwalib::CorrelatorContext;
+use ndarray::{Array2, Array3, ArrayView2, Axis};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+/// Maximum number of StEFCal iterations per fine channel and polarisation
+const MAX_ITERATIONS: usize = 100;
+/// StEFCal stops once the relative change in the gains is below this
+const CONVERGENCE_TOLERANCE: f64 = 1e-6;
+
+/// Solves for a complex gain per antenna, fine channel and polarisation (XX and YY) from the corrected data vector
+/// averaged over timesteps, `averaged` (fine_chan, baseline), assuming a point source model at the phase centre.
+///
+/// StEFCal is used to solve for the gains of each fine channel and polarisation independently. Antennas flagged in the metafits are not used and their gains are NaN.
+/// Gains are referenced to the phase of the first unflagged antenna and their amplitudes include the (unknown) flux
+/// of the source, so they are only meaningful relative to each other.
+///
+/// Returns an array of [ant][fine chan][pol] where pol is XX or YY.
+pub fn solve_gains(
+    context: &CorrelatorContext,
+    averaged: &Array2<Jones<f32>>,
+) -> Array3<Complex<f64>> {
+    info!("Starting solve_gains()...");
+
+    let num_ants = context.metafits_context.num_ants;
+
+    let flagged_ants: Vec<bool> = context
+        .metafits_context
+        .antennas
+        .iter()
+        .map(|a| a.rfinput_x.flagged || a.rfinput_y.flagged)
+        .collect();
+
+    debug!(
+        "{} of {} antennas are flagged and will not be used",
+        flagged_ants.iter().filter(|f| **f).count(),
+        num_ants
+    );
+
+    let mut gains = Array3::<Complex<f64>>::zeros((num_ants, averaged.dim().0, 2));
+
+    // Solve each fine channel in parallel
+    gains
+        .axis_iter_mut(Axis(1))
+        .into_par_iter()
+        .zip(averaged.axis_iter(Axis(0)))
+        .for_each(|(mut fine_chan_gains, fine_chan_vis)| {
+            for (pol_index, pol) in [0, 3].iter().enumerate() {
+                // Build the (Hermitian) visibility matrix for this pol
+                let mut vis_matrix = Array2::<Complex<f64>>::zeros((num_ants, num_ants));
+
+                for (bl, vis) in context
+                    .metafits_context
+                    .baselines
+                    .iter()
+                    .zip(fine_chan_vis.iter())
+                {
+                    let v = Complex::new(vis[*pol].re as f64, vis[*pol].im as f64);
+
+                    if bl.ant1_index != bl.ant2_index && v.re.is_finite() && v.im.is_finite() {
+                        vis_matrix[[bl.ant1_index, bl.ant2_index]] = v;
+                        vis_matrix[[bl.ant2_index, bl.ant1_index]] = v.conj();
+                    }
+                }
+
+                fine_chan_gains
+                    .index_axis_mut(Axis(1), pol_index)
+                    .assign(&ndarray::Array1::from(stefcal(
+                        vis_matrix.view(),
+                        &flagged_ants,
+                    )));
+            }
+        });
+
+    gains
+}
+
+/// StEFCal (Salvini & Wijnholds 2014) for a point source model of unit flux at the phase centre.
+///
+/// `vis_matrix` is [ant1][ant2] with zeros on the diagonal. Returns one gain per antenna such that
+/// vis_matrix[p][q] ~= g[p] * conj(g[q]), with flagged (or all zero) antennas set to NaN.
+fn stefcal(vis_matrix: ArrayView2<Complex<f64>>, flagged_ants: &[bool]) -> Vec<Complex<f64>> {
+    let num_ants = flagged_ants.len();
+
+    // Don't use antennas which are flagged or have no data
+    let used: Vec<bool> = (0..num_ants)
+        .map(|p| !flagged_ants[p] && vis_matrix.row(p).iter().any(|v| v.norm_sqr() > 0.0))
+        .collect();
+
+    let mut gains: Vec<Complex<f64>> = used
+        .iter()
+        .map(|u| Complex::new(if *u { 1.0 } else { 0.0 }, 0.0))
+        .collect();
+
+    for iteration in 0..MAX_ITERATIONS {
+        let previous_gains = gains.clone();
+
+        for p in (0..num_ants).filter(|p| used[*p]) {
+            let mut numerator = Complex::new(0.0, 0.0);
+            let mut denominator = 0.0;
+
+            for q in (0..num_ants).filter(|q| used[*q] && *q != p) {
+                numerator += vis_matrix[[p, q]] * previous_gains[q];
+                denominator += previous_gains[q].norm_sqr();
+            }
+
+            gains[p] = if denominator > 0.0 {
+                numerator / denominator
+            } else {
+                Complex::new(0.0, 0.0)
+            };
+        }
+
+        // Every second iteration, average with the previous gains to stop oscillation
+        if iteration % 2 == 1 {
+            for (g, previous) in gains.iter_mut().zip(&previous_gains) {
+                *g = (*g + previous) / 2.0;
+            }
+        }
+
+        let change: f64 = gains
+            .iter()
+            .zip(&previous_gains)
+            .map(|(g, previous)| (g - previous).norm_sqr())
+            .sum::<f64>()
+            .sqrt();
+        let norm: f64 = gains.iter().map(|g| g.norm_sqr()).sum::<f64>().sqrt();
+
+        if norm == 0.0 || change / norm < CONVERGENCE_TOLERANCE {
+            trace!("StEFCal converged after {} iterations", iteration + 1);
+            break;
+        }
+    }
+
+    // Reference the phases to the first used antenna
+    let reference = used
+        .iter()
+        .position(|u| *u)
+        .map(|p| gains[p])
+        .filter(|g| g.norm() > 0.0)
+        .map(|g| g.conj() / g.norm())
+        .unwrap_or(Complex::new(1.0, 0.0));
+
+    gains
+        .iter()
+        .zip(&used)
+        .map(|(g, u)| {
+            if *u {
+                g * reference
+            } else {
+                Complex::new(f64::NAN, f64::NAN)
+            }
+        })
+        .collect()
+}
+
+/// Outputs one binary file per coarse channel for an observation from the `gains` ([ant][fine chan][pol]) solved for
+/// the coarse channels in `coarse_chan_range`.
+///
+/// Each file is named OBSID_gains_NFINECHANSchans_128T_chRECCHAN.dat (128 is the number of tiles which may vary)
+///
+/// File format 5 floats * num fine channels per coarse * tiles:
+/// Slowest moving -> fastest moving
+/// [ant][fine chan freq][XX amp][XX phase][YY amp][YY phase]
+///
+///     fine chan freq (MHz)
+///     amplitude(XX) (relative)
+///     phase(XX) (deg)
+///     amplitude(YY) (relative)
+///     phase(YY) (deg)
+pub fn output_gains(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    gains: &Array3<Complex<f64>>,
+    coarse_chan_range: &Range<usize>,
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_gains()...");
+
+    let fine_chans_per_coarse = context.metafits_context.num_corr_fine_chans_per_coarse;
+
+    // Produce one gains file per coarse channel
+    for (coarse_chan_loop_index, coarse_chan_index) in coarse_chan_range.clone().enumerate() {
+        // The range may span coarse channels we were not given files for
+        if !context
+            .provided_coarse_chan_indices
+            .contains(&coarse_chan_index)
+        {
+            debug!(
+                "Skipping coarse channel index: {} as no data was provided for it",
+                coarse_chan_index
+            );
+            continue;
+        }
+
+        // Open a file for writing
+        let output_filename = Path::new(output_dir).join(format!(
+            "{}_gains_{}chans_{}T_ch{}.dat",
+            context.metafits_context.obs_id,
+            fine_chans_per_coarse,
+            context.metafits_context.num_ants,
+            context.coarse_chans[coarse_chan_index].rec_chan_number
+        ));
+
+        // Establish the starting index for the fine channel frequency array. It is for all channels whether we
+        // provided data or not
+        let fine_chan_freq_index = coarse_chan_index * fine_chans_per_coarse;
+
+        // The gains array contains the fine channels of every coarse channel in the range
+        let gains_fine_chan_offset = coarse_chan_loop_index * fine_chans_per_coarse;
+
+        let output_file = File::create(&output_filename)
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+        let mut writer = BufWriter::new(&output_file);
+
+        for ant_gains in gains.outer_iter() {
+            for fine_chan_index in 0..fine_chans_per_coarse {
+                let fine_chan_freq_mhz = (context.metafits_context.metafits_fine_chan_freqs_hz
+                    [fine_chan_freq_index + fine_chan_index]
+                    / 1000000.0) as f32;
+
+                let xx = ant_gains[[gains_fine_chan_offset + fine_chan_index, 0]];
+                let yy = ant_gains[[gains_fine_chan_offset + fine_chan_index, 1]];
+
+                let float_vec = [
+                    fine_chan_freq_mhz,
+                    xx.norm() as f32,
+                    xx.arg().to_degrees() as f32,
+                    yy.norm() as f32,
+                    yy.arg().to_degrees() as f32,
+                ];
+
+                writer
+                    .write_all(
+                        &float_vec
+                            .iter()
+                            .flat_map(|f| f.to_le_bytes())
+                            .collect::<Vec<u8>>(),
+                    )
+                    .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+            }
+        }
+
+        writer
+            .flush()
+            .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+        info!("Done! {} written.", output_filename.display());
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stefcal() {
+        let true_gains = [
+            Complex::from_polar(1.0, 0.0),
+            Complex::from_polar(2.0, 0.5),
+            Complex::from_polar(0.5, -1.0),
+            Complex::from_polar(1.5, 2.0),
+            Complex::from_polar(9.0, 1.0),
+        ];
+        // The last antenna is flagged
+        let flagged_ants = [false, false, false, false, true];
+
+        let mut vis_matrix = Array2::<Complex<f64>>::zeros((5, 5));
+        for p in 0..5 {
+            for q in 0..5 {
+                if p != q {
+                    vis_matrix[[p, q]] = true_gains[p] * true_gains[q].conj();
+                }
+            }
+        }
+
+        let gains = stefcal(vis_matrix.view(), &flagged_ants);
+
+        // Antenna 0 is the reference, which already has a phase of 0
+        for p in 0..4 {
+            assert!((gains[p] - true_gains[p]).norm() < 1e-4);
+        }
+        assert!(gains[4].is_nan());
+    }
+}
@@ -0,0 +1,383 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use crate::processing::Corrections;
+use mwalib::CorrelatorContext;
+use std::io::{self, Read, Write};
+
+/// The first bytes of every autos and fringes file written with a header
+pub const MAGIC: [u8; 8] = *b"MWAXSTAT";
+/// The version of the header format. Increment this if the header changes
+pub const HEADER_VERSION: u32 = 1;
+/// The size in bytes of a version 1 header
+pub const HEADER_SIZE: u32 = 64;
+
+/// Whether the autos and fringes files start with a `FileHeader`. The existing M&C plots do not expect one, so the
+/// default is `Legacy`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Write a `FileHeader` before the records
+    Header,
+    /// Only the records, as expected by the existing M&C plots (the dimensions are in the filename)
+    #[default]
+    Legacy,
+}
+
+/// The layout of the records which follow the header
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum RecordLayout {
+    /// [ant][fine chan freq][XX][YY]
+    Autos = 1,
+    /// [ant][fine chan freq][XX min][XX max][XX stddev][YY min][YY max][YY stddev]
+    AutosStats = 2,
+    /// [ant][fine chan freq][XY pow][XY phase][YX pow][YX phase]
+    AutosCrossPols = 3,
+    /// [ant][timestep][fine chan freq][XX][YY]
+    AutosWaterfall = 4,
+    /// [ant1][ant2][fine chan freq][XX phase][YY phase]
+    FringesV1 = 5,
+    /// [ant1][ant2][fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps]
+    FringesV2 = 6,
+    /// [ant1][ant2][fine chan freq][XX phase][YY phase][XX amp][YY amp][num timesteps][XY phase][YX phase][XY amp][YX amp]
+    FringesV3 = 7,
+}
+
+impl RecordLayout {
+    /// The number of floats in each record
+    pub fn floats_per_record(self) -> u32 {
+        match self {
+            RecordLayout::Autos => 3,
+            RecordLayout::AutosStats => 7,
+            RecordLayout::AutosCrossPols => 5,
+            RecordLayout::AutosWaterfall => 3,
+            RecordLayout::FringesV1 => 3,
+            RecordLayout::FringesV2 => 6,
+            RecordLayout::FringesV3 => 10,
+        }
+    }
+
+    fn from_u32(value: u32) -> Option<Self> {
+        [
+            RecordLayout::Autos,
+            RecordLayout::AutosStats,
+            RecordLayout::AutosCrossPols,
+            RecordLayout::AutosWaterfall,
+            RecordLayout::FringesV1,
+            RecordLayout::FringesV2,
+            RecordLayout::FringesV3,
+        ]
+        .into_iter()
+        .find(|layout| *layout as u32 == value)
+    }
+}
+
+/// What the autos and fringes files (their names and headers) say about the observation, so they can be written
+/// without a `CorrelatorContext`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ObsInfo {
+    pub obs_id: u32,
+    pub num_ants: usize,
+    pub num_fine_chans: usize,
+    pub corr_int_time_ms: u64,
+    /// The receiver channel number of each coarse channel (indexed like `CorrelatorContext::coarse_chans`)
+    pub rec_chan_numbers: Vec<usize>,
+    /// The GPS time (ms) of the start of each timestep (indexed like `CorrelatorContext::timesteps`)
+    pub timestep_gps_times_ms: Vec<u64>,
+}
+
+impl ObsInfo {
+    pub fn from_context(context: &CorrelatorContext) -> Self {
+        ObsInfo {
+            obs_id: context.metafits_context.obs_id,
+            num_ants: context.metafits_context.num_ants,
+            num_fine_chans: context.metafits_context.num_corr_fine_chans_per_coarse,
+            corr_int_time_ms: context.metafits_context.corr_int_time_ms,
+            rec_chan_numbers: context
+                .coarse_chans
+                .iter()
+                .map(|c| c.rec_chan_number)
+                .collect(),
+            timestep_gps_times_ms: context.timesteps.iter().map(|t| t.gps_time_ms).collect(),
+        }
+    }
+}
+
+/// Describes the contents of an autos or fringes file, so it can be read without parsing the filename.
+///
+/// It is written as 64 bytes of little endian values:
+///
+///     magic "MWAXSTAT" (8 bytes)
+///     header version (u32)
+///     header size in bytes (u32), the records start after this many bytes
+///     GPS time of the start of the first timestep (u64 ms)
+///     GPS time of the end of the last timestep (u64 ms)
+///     obsid (u32)
+///     receiver channel number (u32)
+///     number of tiles (u32)
+///     number of fine channels (u32)
+///     number of timesteps (u32)
+///     record layout (u32, see `RecordLayout`)
+///     floats per record (u32)
+///     corrections applied (u32 bit mask: 1 = Van Vleck, 2 = cable lengths, 4 = digital gains, 8 = passband gains,
+///     16 = geometry)
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileHeader {
+    pub start_gps_time_ms: u64,
+    pub end_gps_time_ms: u64,
+    pub obs_id: u32,
+    pub rec_chan_number: u32,
+    pub num_tiles: u32,
+    pub num_fine_chans: u32,
+    pub num_timesteps: u32,
+    pub record_layout: RecordLayout,
+    pub corrections: Corrections,
+}
+
+impl FileHeader {
+    /// The header of a file for the coarse channel `coarse_chan_index` made from the timesteps from
+    /// `timestep_indices`, with `corrections` applied. An empty `timestep_indices` is an `InvalidArgument` error.
+    pub fn new(
+        obs: &ObsInfo,
+        coarse_chan_index: usize,
+        timestep_indices: &[usize],
+        record_layout: RecordLayout,
+        corrections: &Corrections,
+    ) -> Result<Self, MwaxStatsError> {
+        let (Some(first_timestep_index), Some(last_timestep_index)) =
+            (timestep_indices.first(), timestep_indices.last())
+        else {
+            return Err(MwaxStatsError::InvalidArgument(format!(
+                "No timesteps for the header of coarse channel {}",
+                coarse_chan_index
+            )));
+        };
+        let first_timestep_gps_time_ms = obs.timestep_gps_times_ms[*first_timestep_index];
+        let last_timestep_gps_time_ms = obs.timestep_gps_times_ms[*last_timestep_index];
+
+        Ok(FileHeader {
+            start_gps_time_ms: first_timestep_gps_time_ms,
+            end_gps_time_ms: last_timestep_gps_time_ms + obs.corr_int_time_ms,
+            obs_id: obs.obs_id,
+            rec_chan_number: obs.rec_chan_numbers[coarse_chan_index] as u32,
+            num_tiles: obs.num_ants as u32,
+            num_fine_chans: obs.num_fine_chans as u32,
+            num_timesteps: timestep_indices.len() as u32,
+            record_layout,
+            corrections: *corrections,
+        })
+    }
+
+    /// Writes the header (see `FileHeader`)
+    pub fn write<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        let corrections = &self.corrections;
+        let corrections_mask = [
+            corrections.van_vleck,
+            corrections.cable_lengths,
+            corrections.digital_gains,
+            corrections.passband_gains,
+            corrections.geometry,
+        ]
+        .iter()
+        .enumerate()
+        .filter(|(_, applied)| **applied)
+        .fold(0u32, |mask, (bit, _)| mask | 1 << bit);
+
+        writer.write_all(&MAGIC)?;
+        for value in [HEADER_VERSION, HEADER_SIZE] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for value in [self.start_gps_time_ms, self.end_gps_time_ms] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+        for value in [
+            self.obs_id,
+            self.rec_chan_number,
+            self.num_tiles,
+            self.num_fine_chans,
+            self.num_timesteps,
+            self.record_layout as u32,
+            self.record_layout.floats_per_record(),
+            corrections_mask,
+        ] {
+            writer.write_all(&value.to_le_bytes())?;
+        }
+
+        Ok(())
+    }
+
+    /// Reads a header written by `write`, leaving the reader at the start of the records. Headers from later versions
+    /// are rejected, as their values may not mean what this version thinks they do.
+    pub fn read<R: Read>(reader: &mut R) -> io::Result<Self> {
+        let invalid = |reason: String| io::Error::new(io::ErrorKind::InvalidData, reason);
+
+        let mut magic = [0u8; 8];
+        reader.read_exact(&mut magic)?;
+        if magic != MAGIC {
+            return Err(invalid(
+                "not an mwax_stats file with a header (legacy files have no header)".to_string(),
+            ));
+        }
+
+        let read_u32 = |reader: &mut R| -> io::Result<u32> {
+            let mut bytes = [0u8; 4];
+            reader.read_exact(&mut bytes)?;
+            Ok(u32::from_le_bytes(bytes))
+        };
+        let version = read_u32(reader)?;
+        if version > HEADER_VERSION {
+            return Err(invalid(format!(
+                "header version {} is newer than the supported version {}",
+                version, HEADER_VERSION
+            )));
+        }
+        let header_size = read_u32(reader)?;
+        if header_size < HEADER_SIZE {
+            return Err(invalid(format!("header size {} is too small", header_size)));
+        }
+
+        let read_u64 = |reader: &mut R| -> io::Result<u64> {
+            let mut bytes = [0u8; 8];
+            reader.read_exact(&mut bytes)?;
+            Ok(u64::from_le_bytes(bytes))
+        };
+        let start_gps_time_ms = read_u64(reader)?;
+        let end_gps_time_ms = read_u64(reader)?;
+
+        let obs_id = read_u32(reader)?;
+        let rec_chan_number = read_u32(reader)?;
+        let num_tiles = read_u32(reader)?;
+        let num_fine_chans = read_u32(reader)?;
+        let num_timesteps = read_u32(reader)?;
+        let layout = read_u32(reader)?;
+        let floats_per_record = read_u32(reader)?;
+        let corrections_mask = read_u32(reader)?;
+
+        let record_layout = RecordLayout::from_u32(layout)
+            .filter(|layout| layout.floats_per_record() == floats_per_record)
+            .ok_or_else(|| {
+                invalid(format!(
+                    "unknown record layout {} with {} floats per record",
+                    layout, floats_per_record
+                ))
+            })?;
+
+        // Skip anything after the values we know about
+        io::copy(
+            &mut reader.take((header_size - HEADER_SIZE) as u64),
+            &mut io::sink(),
+        )?;
+
+        let applied = |bit: u32| corrections_mask & (1 << bit) != 0;
+
+        Ok(FileHeader {
+            start_gps_time_ms,
+            end_gps_time_ms,
+            obs_id,
+            rec_chan_number,
+            num_tiles,
+            num_fine_chans,
+            num_timesteps,
+            record_layout,
+            corrections: Corrections {
+                van_vleck: applied(0),
+                cable_lengths: applied(1),
+                digital_gains: applied(2),
+                passband_gains: applied(3),
+                geometry: applied(4),
+            },
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read_header() {
+        let header = FileHeader {
+            start_gps_time_ms: 1317706936000,
+            end_gps_time_ms: 1317707048000,
+            obs_id: 1317706936,
+            rec_chan_number: 123,
+            num_tiles: 128,
+            num_fine_chans: 64,
+            num_timesteps: 14,
+            record_layout: RecordLayout::FringesV2,
+            corrections: Corrections {
+                cable_lengths: true,
+                geometry: true,
+                ..Default::default()
+            },
+        };
+
+        let mut bytes = Vec::new();
+        header.write(&mut bytes).unwrap();
+        assert_eq!(bytes.len(), HEADER_SIZE as usize);
+        assert_eq!(bytes[0..8], MAGIC);
+        // Cable lengths and geometry are bits 1 and 4
+        assert_eq!(bytes[60..64], 18u32.to_le_bytes());
+
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let u64_at =
+            |offset: usize| u64::from_le_bytes(bytes[offset..offset + 8].try_into().unwrap());
+        assert_eq!(u32_at(8), HEADER_VERSION);
+        assert_eq!(u32_at(12), HEADER_SIZE);
+        assert_eq!(u64_at(16), 1317706936000);
+        assert_eq!(u64_at(24), 1317707048000);
+        assert_eq!(u32_at(32), 1317706936);
+        assert_eq!(u32_at(36), 123);
+        assert_eq!(u32_at(40), 128);
+        assert_eq!(u32_at(44), 64);
+        assert_eq!(u32_at(48), 14);
+        assert_eq!(u32_at(52), RecordLayout::FringesV2 as u32);
+        assert_eq!(u32_at(56), 6);
+
+        // Followed by a record
+        bytes.extend_from_slice(&1.5f32.to_le_bytes());
+
+        let mut reader = bytes.as_slice();
+        assert_eq!(FileHeader::read(&mut reader).unwrap(), header);
+        assert_eq!(reader, 1.5f32.to_le_bytes());
+
+        // A legacy file
+        let legacy = [0u8; 64];
+        assert_eq!(
+            FileHeader::read(&mut legacy.as_slice()).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+
+        // A later version
+        let mut later = bytes.clone();
+        later[8..12].copy_from_slice(&(HEADER_VERSION + 1).to_le_bytes());
+        let error = FileHeader::read(&mut later.as_slice()).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+        assert!(error.to_string().contains("header version 2"));
+    }
+
+    #[test]
+    fn test_new_header() {
+        let obs = ObsInfo {
+            obs_id: 1317706936,
+            num_ants: 128,
+            num_fine_chans: 64,
+            corr_int_time_ms: 2000,
+            rec_chan_numbers: vec![109, 110],
+            timestep_gps_times_ms: (0..4).map(|t| 1317706936000 + t * 2000).collect(),
+        };
+        let corrections = Corrections::default();
+
+        let header = FileHeader::new(&obs, 1, &[1, 3], RecordLayout::Autos, &corrections).unwrap();
+        assert_eq!(header.start_gps_time_ms, 1317706938000);
+        assert_eq!(header.end_gps_time_ms, 1317706944000);
+        assert_eq!(header.rec_chan_number, 110);
+        assert_eq!(header.num_timesteps, 2);
+
+        assert!(matches!(
+            FileHeader::new(&obs, 1, &[], RecordLayout::Autos, &corrections),
+            Err(MwaxStatsError::InvalidArgument(_))
+        ));
+    }
+}
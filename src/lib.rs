@@ -0,0 +1,31 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//! The processing behind the `mwax_stats` and `mwax_packet_stats` executables, plus readers for the files they write
+//! (see `reader`).
+//!
+//! The executables only parse their command line and call:
+//!
+//! * `stats::run` - everything `mwax_stats` does, from a `stats::StatsOptions`. The steps are public too, so the
+//!   results can be kept in memory instead of written: `stats::select_ranges`, `stats::get_corrections` and
+//!   `stats::get_calibrator_sums` (the time summed visibilities, bispectra and RFI occupancy), then
+//!   `autos::get_autocorrelations` and `fringes::get_fringe_set` for the `AutoSpectra` and `FringeSet` of each coarse
+//!   channel (`autos::compute_auto_spectra` works them out from raw data already in memory). The files are written by
+//!   the `AutosWriter` and `FringesWriter` passed to `autos::output_autocorrelations` and `fringes::output_fringes`.
+//! * `subfile::process_subfile_packet_map_data` - everything `mwax_packet_stats` does. `subfile::get_packet_stats`
+//!   returns the packets lost without writing them.
+pub mod autos;
+pub mod closures;
+pub mod delays;
+pub mod errors;
+pub mod fringes;
+pub mod gains;
+pub mod header;
+pub mod numpy;
+pub mod processing;
+pub mod reader;
+pub mod rfi;
+pub mod solutions;
+pub mod sources;
+pub mod stats;
+pub mod subfile;
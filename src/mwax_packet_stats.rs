@@ -1,11 +1,10 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
-mod subfile;
-
 use clap::{crate_authors, crate_description, crate_version, App, Arg};
 use gethostname::gethostname;
-use log::debug;
+use log::{debug, error};
+use mwax_stats::{errors::MwaxStatsError, numpy::FileFormat, subfile};
 use std::{env, ffi::OsString, fmt::Debug, path::Path};
 
 /// This is main entry point of the executable.
@@ -17,12 +16,15 @@ use std::{env, ffi::OsString, fmt::Debug, path::Path};
 ///
 /// # Returns
 ///
-/// * None
+/// * None. On error the process exits with the code from `MwaxStatsError::exit_code`
 ///
 fn main() {
     env_logger::try_init().unwrap_or(());
     debug!("start main");
-    main_with_args(env::args());
+    if let Err(e) = main_with_args(env::args()) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
     debug!("end main");
 }
 
@@ -35,9 +37,9 @@ fn main() {
 ///
 /// # Returns
 ///
-/// * N/A
+/// * Result containing nothing if the packet stats were written, or the MwaxStatsError which stopped us
 ///
-pub(crate) fn main_with_args<I, T>(args: I)
+pub(crate) fn main_with_args<I, T>(args: I) -> Result<(), MwaxStatsError>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
@@ -64,6 +66,15 @@ where
                 .takes_value(true)
                 .required(true)
                 .help("Specify the directory to write output files to."),
+        )
+        .arg(
+            Arg::with_name("file-format")
+                .long("file-format")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["dat", "npy"])
+                .default_value("dat")
+                .help("Write the packet stats as a binary .dat file or a .npy file."),
         );        
 
     let arg_matches = app.get_matches_from(args);
@@ -73,8 +84,12 @@ where
     // Collect inputs from the command line
     let subfile_name = arg_matches.value_of("subfile_name").unwrap();
     let output_dir = arg_matches.value_of("output-dir").unwrap();
+    let file_format: FileFormat = arg_matches
+        .value_of("file-format")
+        .unwrap()
+        .parse()
+        .map_err(|e| MwaxStatsError::InvalidArgument(format!("Invalid --file-format: {}", e)))?;
     
     // Read Packet stats
-    subfile::process_subfile_packet_map_data(Path::new(subfile_name), Path::new(output_dir), hostname.to_str().unwrap()).expect("Error");    
-
+    subfile::process_subfile_packet_map_data(Path::new(subfile_name), Path::new(output_dir), &hostname.to_string_lossy(), file_format)
 }
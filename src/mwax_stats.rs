@@ -1,14 +1,10 @@
 // This Source Code Form is subject to the terms of the Mozilla Public
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at http://mozilla.org/MPL/2.0/.
-mod autos;
-mod errors;
-mod fringes;
-mod processing;
-
 use clap::{crate_authors, crate_description, crate_version, App, Arg};
-use log::{debug, info};
-use mwalib::CorrelatorContext;
+use log::{debug, error, info};
+use mwax_stats::errors::MwaxStatsError;
+use mwax_stats::{closures, fringes, header, processing, sources, stats};
 use std::{env, ffi::OsString, fmt::Debug};
 
 /// This is main entry point of the executable.
@@ -20,12 +16,15 @@ use std::{env, ffi::OsString, fmt::Debug};
 ///
 /// # Returns
 ///
-/// * None
+/// * None. On error the process exits with the code from `MwaxStatsError::exit_code`
 ///
 fn main() {
     env_logger::try_init().unwrap_or(());
     info!("start main");
-    main_with_args(env::args());
+    if let Err(e) = main_with_args(env::args()) {
+        error!("{}", e);
+        std::process::exit(e.exit_code());
+    }
     info!("end main");
 }
 
@@ -38,9 +37,9 @@ fn main() {
 ///
 /// # Returns
 ///
-/// * None
+/// * Result containing nothing if all outputs were written, or the MwaxStatsError which stopped us
 ///
-pub(crate) fn main_with_args<I, T>(args: I)
+pub(crate) fn main_with_args<I, T>(args: I) -> Result<(), MwaxStatsError>
 where
     I: IntoIterator<Item = T>,
     T: Into<OsString> + Clone,
@@ -71,14 +70,241 @@ where
                 .short("t")
                 .takes_value(false)
                 .required(false)
-                .help("Use any timestep if no good (post quaktime) timestep can be found."),
+                .help("Use any timestep if no good (post quaktime) timestep can be found (i.e. fall back from --selection common-good to common)."),
+        )
+        .arg(
+            Arg::with_name("selection")
+                .long("selection")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["common-good", "common", "any-provided"])
+                .default_value("common-good")
+                .help("Which timesteps and coarse channels to use. common-good = common to all provided coarse channels and after the quack time, common = common to all provided coarse channels, any-provided = every provided timestep and coarse channel."),
+        )
+        .arg(
+            Arg::with_name("timesteps")
+                .long("timesteps")
+                .takes_value(true)
+                .required(false)
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .validator(|t| match t.parse::<usize>() {
+                    Ok(_) => Ok(()),
+                    Err(_) => t.parse::<processing::TimestepSelection>().map(|_| ()),
+                })
+                .help("Use only the first, middle or last of the timesteps chosen by --selection (or all of them, the default). Or use these contiguous timestep indices e.g. 4,5,6 instead of --selection (all provided coarse channels are used unless --coarse-chans is given)."),
+        )
+        .arg(
+            Arg::with_name("gps-times")
+                .long("gps-times")
+                .takes_value(true)
+                .required(false)
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .conflicts_with("timesteps")
+                .validator(|g| g.parse::<f64>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Use the timesteps containing these GPS times (s) e.g. 1244973700,1244973708 instead of --selection, like --timesteps with indices (the timesteps must be contiguous)."),
+        )
+        .arg(
+            Arg::with_name("coarse-chans")
+                .long("coarse-chans")
+                .takes_value(true)
+                .required(false)
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .validator(|c| c.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Use these contiguous coarse channel indices e.g. 0,1,2 instead of --selection (all provided timesteps are used unless --timesteps is given)."),
         )
         .arg(
             Arg::with_name("memory-limit-gb")
                 .short("l")
                 .takes_value(true)
                 .required(false)
-                .help("Try to limit memory use to this number of GB."),
+                .help("Limit the memory used to read and correct the data (calibrators only) to this number of GB. Without --chunked only the first timesteps which fit are used."),
+        )
+        .arg(
+            Arg::with_name("chunked")
+                .long("chunked")
+                .required(false)
+                .help("Read and correct the data in chunks of timesteps which fit within the memory limit (-l), instead of dropping the timesteps which do not fit."),
+        )
+        .arg(
+            Arg::with_name("average-autos")
+                .short("a")
+                .long("average-autos")
+                .takes_value(false)
+                .required(false)
+                .help("Average the autocorrelations over all selected timesteps instead of using the last timestep."),
+        )
+        .arg(
+            Arg::with_name("autos-stats")
+                .long("autos-stats")
+                .takes_value(false)
+                .required(false)
+                .requires("average-autos")
+                .help("Also output the min, max and standard deviation of the averaged autocorrelations."),
+        )
+        .arg(
+            Arg::with_name("autos-waterfall")
+                .short("w")
+                .long("autos-waterfall")
+                .takes_value(false)
+                .required(false)
+                .help("Also output an autocorrelation waterfall (every selected timestep) per coarse channel."),
+        )
+        .arg(
+            Arg::with_name("autos-cross-pols")
+                .long("autos-cross-pols")
+                .takes_value(false)
+                .required(false)
+                .help("Also output the XY/YX cross-polarisation power and phase of the autocorrelations."),
+        )
+        .arg(
+            Arg::with_name("fringes-version")
+                .long("fringes-version")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["1", "2", "3"])
+                .default_value("1")
+                .help("Fringes record layout. 1 = phases only, 2 = phases, amplitudes and number of timesteps, 3 = version 2 plus XY/YX phases and amplitudes."),
+        )
+        .arg(
+            Arg::with_name("header")
+                .long("header")
+                .takes_value(false)
+                .required(false)
+                .help("Start the autos and fringes files with a header describing their contents. The existing M&C plots expect files without one."),
+        )
+        .arg(
+            Arg::with_name("file-format")
+                .long("file-format")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["dat", "npy", "npz"])
+                .default_value("dat")
+                .help("Write the autos and fringes as binary .dat files, as a .npy file in place of each .dat file, or as a single .npz bundle for the observation."),
+        )
+        .arg(
+            Arg::with_name("delays")
+                .long("delays")
+                .takes_value(false)
+                .required(false)
+                .help("Also output the delay of each baseline fitted from the fringe phase slope (calibrators only)."),
+        )
+        .arg(
+            Arg::with_name("gains")
+                .long("gains")
+                .takes_value(false)
+                .required(false)
+                .help("Also solve for and output the gain of each tile, assuming a point source at the phase centre (calibrators only)."),
+        )
+        .arg(
+            Arg::with_name("solutions")
+                .long("solutions")
+                .takes_value(false)
+                .required(false)
+                .requires("gains")
+                .help("Also write the tile gains as a (hyperdrive format) calibration solutions FITS file."),
+        )
+        .arg(
+            Arg::with_name("calsols")
+                .long("calsols")
+                .takes_value(true)
+                .required(false)
+                .help("Apply this calibration solutions file (hyperdrive FITS or AO .bin) before producing fringes etc."),
+        )
+        .arg(
+            Arg::with_name("force-fringes")
+                .long("force-fringes")
+                .takes_value(false)
+                .required(false)
+                .help("Produce the fringes etc. even if the observation is not marked as a calibrator in the metafits."),
+        )
+        .arg(
+            Arg::with_name("phase-centre")
+                .long("phase-centre")
+                .takes_value(true)
+                .required(false)
+                .allow_hyphen_values(true)
+                .validator(|p| sources::parse_phase_centre(&p).map(|_| ()))
+                .help("Phase the fringes etc. to this RA,DEC in degrees e.g. 79.96,-45.78 or one of these calibrators: 3C444, 3C161, CasA, CenA, CygA, ForA, HerA, HydA, PicA, TauA, VirA (needs the geometric correction)."),
+        )
+        .arg(
+            Arg::with_name("cable-lengths")
+                .long("cable-lengths")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["auto", "on", "off"])
+                .default_value("auto")
+                .help("Correct for cable lengths before producing fringes etc. auto = only if the correlator has not already (from the metafits)."),
+        )
+        .arg(
+            Arg::with_name("digital-gains")
+                .long("digital-gains")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["auto", "on", "off"])
+                .default_value("auto")
+                .help("Correct for digital gains before producing fringes etc. auto = on."),
+        )
+        .arg(
+            Arg::with_name("passband-gains")
+                .long("passband-gains")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["auto", "on", "off"])
+                .default_value("auto")
+                .help("Correct for passband gains before producing fringes etc. auto = only if the correlator has not already derippled (from the metafits)."),
+        )
+        .arg(
+            Arg::with_name("geometry")
+                .long("geometry")
+                .takes_value(true)
+                .required(false)
+                .possible_values(&["auto", "on", "off"])
+                .default_value("auto")
+                .help("Correct for geometric delays before producing fringes etc. auto = only if the correlator has not already (from the metafits)."),
+        )
+        .arg(
+            Arg::with_name("van-vleck")
+                .long("van-vleck")
+                .takes_value(false)
+                .required(false)
+                .help("Apply the Van Vleck correction to the autos (which are then read through birli) and fringes etc."),
+        )
+        .arg(
+            Arg::with_name("rfi-flagging")
+                .long("rfi-flagging")
+                .takes_value(false)
+                .required(false)
+                .help("Flag RFI (with a built-in SumThreshold flagger, along frequency within each timestep only) before producing fringes etc, and output the RFI occupancy per fine channel and tile. RFI covering a whole coarse channel in a timestep is not flagged."),
+        )
+        .arg(
+            Arg::with_name("closure-triads")
+                .long("closure-triads")
+                .takes_value(true)
+                .required(false)
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .validator(|t| closures::parse_triad(&t).map(|_| ()))
+                .conflicts_with("closure-ants")
+                .help("Also output the closure phase of these antenna triads (antenna indices) e.g. 0-1-2,0-1-3 (calibrators only)."),
+        )
+        .arg(
+            Arg::with_name("closure-ants")
+                .long("closure-ants")
+                .takes_value(true)
+                .required(false)
+                .multiple(true)
+                .use_delimiter(true)
+                .require_delimiter(true)
+                .min_values(3)
+                .validator(|a| a.parse::<usize>().map(|_| ()).map_err(|e| e.to_string()))
+                .help("Also output the closure phase of every triad formed from these antenna indices e.g. 0,1,2,3 (calibrators only)."),
         )
         .arg(Arg::with_name("fits-files").required(true).multiple(true));
 
@@ -89,57 +315,101 @@ where
     // Collect inputs from the command line
     let metafits_filename = arg_matches.value_of("metafits").unwrap();
     let output_dir = arg_matches.value_of("output-dir").unwrap();
-    let use_any_timestep: bool = arg_matches.is_present("use-any-timestep");
     let fits_files: Vec<&str> = arg_matches.values_of("fits-files").unwrap().collect();
-    let max_memory_gb_str: Option<&str> = arg_matches.value_of("memory-limit-gb");
-    let max_memory_gb: Option<f32> = if max_memory_gb_str.is_some() {
-        Some(max_memory_gb_str.unwrap().parse().unwrap())
+    let mut options = stats::StatsOptions::new(metafits_filename, &fits_files, output_dir);
+    options.use_any_timestep = arg_matches.is_present("use-any-timestep");
+    options.average_autos = arg_matches.is_present("average-autos");
+    options.autos_stats = arg_matches.is_present("autos-stats");
+    options.autos_waterfall = arg_matches.is_present("autos-waterfall");
+    options.autos_cross_pols = arg_matches.is_present("autos-cross-pols");
+    options.delays = arg_matches.is_present("delays");
+    options.gains = arg_matches.is_present("gains");
+    options.solutions = arg_matches.is_present("solutions");
+    options.calsols_filename = arg_matches.value_of("calsols").map(String::from);
+    options.rfi_flagging = arg_matches.is_present("rfi-flagging");
+    options.van_vleck = arg_matches.is_present("van-vleck");
+    options.force_fringes = arg_matches.is_present("force-fringes");
+    options.phase_centre = arg_matches
+        .value_of("phase-centre")
+        .map(sources::parse_phase_centre)
+        .transpose()
+        .map_err(MwaxStatsError::InvalidArgument)?;
+    let correction_mode = |name: &str| -> Result<processing::CorrectionMode, MwaxStatsError> {
+        arg_matches
+            .value_of(name)
+            .unwrap()
+            .parse()
+            .map_err(|e| MwaxStatsError::InvalidArgument(format!("Invalid --{}: {}", name, e)))
+    };
+    options.cable_lengths = correction_mode("cable-lengths")?;
+    options.digital_gains = correction_mode("digital-gains")?;
+    options.passband_gains = correction_mode("passband-gains")?;
+    options.geometry = correction_mode("geometry")?;
+    options.closure_triads = if let Some(triads) = arg_matches.values_of("closure-triads") {
+        triads
+            .map(closures::parse_triad)
+            .collect::<Result<_, _>>()
+            .map_err(MwaxStatsError::InvalidArgument)?
+    } else if let Some(ants) = arg_matches.values_of("closure-ants") {
+        closures::triads_from_ants(
+            &ants
+                .map(|a| a.parse())
+                .collect::<Result<Vec<usize>, _>>()
+                .map_err(|e| {
+                    MwaxStatsError::InvalidArgument(format!("Invalid closure antenna: {}", e))
+                })?,
+        )
     } else {
-        None
+        vec![]
     };
-
-    // Although the command line args support it, and so does `processing::get_data()` we really want to only have 1 coarse channel of data passed in
-    // at this stage. So lets check for it and fail if we get >1 channel
-    if fits_files.len() == 1 {
-        // Create correlator context
-        let context = CorrelatorContext::new(metafits_filename, &fits_files)
-            .expect("Failed to create CorrelatoContext");
-
-        // Always print the obs info
-        processing::print_info(&context);
-
-        // Always produce autocorrelations
-        autos::output_autocorrelations(&context, output_dir, use_any_timestep, max_memory_gb);
-
-        // Only produce fringes for calibrator observations (unless we are running in debug)
-        if context.metafits_context.calibrator {
-            let correct_cable_lengths: bool = context.metafits_context.cable_delays_applied
-                == mwalib::CableDelaysApplied::NoCableDelaysApplied;
-            let correct_geometry: bool = context.metafits_context.geometric_delays_applied
-                == mwalib::GeometricDelaysApplied::No;
-
-            let correct_passband_gains: bool = !context.metafits_context.deripple_applied;
-
-            let correct_digital_gains = true;
-            info!("Correcting for cable lengths : {}.", correct_cable_lengths);
-            info!("Correcting for digital gains : {}.", correct_digital_gains);
-            info!("Correcting for passband gains: {}.", correct_passband_gains);
-            info!("Correcting for geometry      : {}.", correct_geometry);
-
-            fringes::output_fringes(
-                &context,
-                output_dir,
-                use_any_timestep,
-                max_memory_gb,
-                correct_cable_lengths,
-                correct_digital_gains,
-                correct_passband_gains,
-                correct_geometry,
-            );
-        } else {
-            info!("Skipping output_fringes() as this is not a calibrator observation.");
-        }
+    options.fringes_version = match arg_matches.value_of("fringes-version").unwrap() {
+        "2" => fringes::FringeRecordVersion::V2,
+        "3" => fringes::FringeRecordVersion::V3,
+        _ => fringes::FringeRecordVersion::V1,
+    };
+    options.output_format = if arg_matches.is_present("header") {
+        header::OutputFormat::Header
     } else {
-        print!("mwax_stats currently only supports a single coarse channel of data. Exiting...")
-    }
+        header::OutputFormat::Legacy
+    };
+    options.file_format = arg_matches
+        .value_of("file-format")
+        .unwrap()
+        .parse()
+        .map_err(MwaxStatsError::InvalidArgument)?;
+    options.selection_policy = match arg_matches.value_of("selection").unwrap() {
+        "common" => processing::SelectionPolicy::Common,
+        "any-provided" => processing::SelectionPolicy::AnyProvided,
+        _ => processing::SelectionPolicy::CommonGood,
+    };
+    let parse_indices = |name: &str| -> Result<Option<Vec<usize>>, MwaxStatsError> {
+        arg_matches
+            .values_of(name)
+            .map(|values| values.map(|v| v.parse()).collect::<Result<Vec<usize>, _>>())
+            .transpose()
+            .map_err(|e| MwaxStatsError::InvalidArgument(format!("Invalid --{}: {}", name, e)))
+    };
+    let timestep_values: Option<Vec<&str>> =
+        arg_matches.values_of("timesteps").map(|t| t.collect());
+    match timestep_values.as_deref() {
+        // A keyword picks from the timesteps chosen by the selection policy
+        Some([keyword]) if keyword.parse::<usize>().is_err() => {
+            options.timestep_selection = keyword.parse().map_err(MwaxStatsError::InvalidArgument)?
+        }
+        _ => options.timestep_indices = parse_indices("timesteps")?,
+    };
+    options.gps_times = arg_matches
+        .values_of("gps-times")
+        .map(|values| values.map(|v| v.parse()).collect::<Result<_, _>>())
+        .transpose()
+        .map_err(|e| MwaxStatsError::InvalidArgument(format!("Invalid --gps-times: {}", e)))?;
+    options.coarse_chan_indices = parse_indices("coarse-chans")?;
+    options.max_memory_gb = arg_matches
+        .value_of("memory-limit-gb")
+        .map(|m| m.parse())
+        .transpose()
+        .map_err(|e| MwaxStatsError::InvalidArgument(format!("Invalid memory limit: {}", e)))?;
+    options.chunked = arg_matches.is_present("chunked");
+
+    stats::run(&options)
 }
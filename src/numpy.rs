@@ -0,0 +1,551 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::autos::{AutoSpectra, AutosWriter};
+use crate::errors::MwaxStatsError;
+use crate::fringes::{FringeRecordVersion, FringeSet, FringesWriter};
+use log::{info, warn};
+use mwalib::CorrelatorContext;
+use ndarray::{concatenate, stack, Array, Array2, Array3, ArrayView2, Axis, Dimension, ShapeError};
+use npyz::{AutoSerialize, DType, TypeStr, WriteOptions, WriterBuilder};
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, Write};
+use std::path::{Path, PathBuf};
+use zip::{write::FileOptions, CompressionMethod, ZipWriter};
+
+/// The kind of files the autos, fringes and packet stats are written to
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum FileFormat {
+    /// The binary .dat files (see `AutosFileWriter`, `FringesFileWriter` and `subfile::write_packet_stats`)
+    #[default]
+    Dat,
+    /// A .npy file in place of each .dat file, holding the same records (see `NpyWriter`)
+    Npy,
+    /// A single .npz bundle for the observation (see `NpzWriter`)
+    Npz,
+}
+
+impl std::str::FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dat" => Ok(FileFormat::Dat),
+            "npy" => Ok(FileFormat::Npy),
+            "npz" => Ok(FileFormat::Npz),
+            _ => Err(format!("'{}' is not one of dat, npy or npz", s)),
+        }
+    }
+}
+
+/// Writes one .npy file of little endian f32 in place of each .dat file. The files have the same names (with a .npy
+/// extension) and the same records, with the record as the last axis:
+///
+///     OBSID_autos_FINECHANSchans_128T_chRECCHAN.npy                       [ant, fine chan, 3]
+///     OBSID_autos_stats_FINECHANSchans_128T_chRECCHAN.npy                 [ant, fine chan, 7]
+///     OBSID_autos_crosspol_FINECHANSchans_128T_chRECCHAN.npy              [ant, fine chan, 5]
+///     OBSID_autos_waterfall_FINECHANSchans_128T_NTIMESTEPSts_chRECCHAN.npy [ant, timestep, fine chan, 3]
+///     OBSID_fringes_[vN_]FINECHANSchans_128T_chRECCHAN.npy                [baseline, fine chan, 3|6|10]
+///
+/// All of the arrays are 3-D except the waterfall; the fringes records have 3, 6 or 10 values for version 1, 2 or 3.
+/// See `AutosFileWriter` and `FringesFileWriter` for the values in each record.
+pub struct NpyWriter<'a> {
+    context: &'a CorrelatorContext,
+    output_dir: PathBuf,
+    record_version: FringeRecordVersion,
+}
+
+impl<'a> NpyWriter<'a> {
+    pub fn new(
+        context: &'a CorrelatorContext,
+        output_dir: &str,
+        record_version: FringeRecordVersion,
+    ) -> Self {
+        NpyWriter {
+            context,
+            output_dir: PathBuf::from(output_dir),
+            record_version,
+        }
+    }
+}
+
+impl AutosWriter for NpyWriter<'_> {
+    fn write_autos(&mut self, spectra: &AutoSpectra) -> Result<(), MwaxStatsError> {
+        let context = self.context;
+        let rec_chan_number = context.coarse_chans[spectra.coarse_chan_index].rec_chan_number;
+        let filename = |product: &str| {
+            self.output_dir.join(format!(
+                "{}_{}_{}chans_{}T_ch{}.npy",
+                context.metafits_context.obs_id,
+                product,
+                context.metafits_context.num_corr_fine_chans_per_coarse,
+                context.metafits_context.num_ants,
+                rec_chan_number
+            ))
+        };
+
+        write_npy_file(
+            &filename("autos"),
+            &autos_records(
+                &spectra.freqs_mhz,
+                &[spectra.xx_db.view(), spectra.yy_db.view()],
+            ),
+        )?;
+
+        if let Some(stats) = &spectra.stats {
+            write_npy_file(
+                &filename("autos_stats"),
+                &autos_records(
+                    &spectra.freqs_mhz,
+                    &[
+                        stats.xx_min_db.view(),
+                        stats.xx_max_db.view(),
+                        stats.xx_stddev_db.view(),
+                        stats.yy_min_db.view(),
+                        stats.yy_max_db.view(),
+                        stats.yy_stddev_db.view(),
+                    ],
+                ),
+            )?;
+        }
+
+        if let Some(cross_pols) = &spectra.cross_pols {
+            write_npy_file(
+                &filename("autos_crosspol"),
+                &autos_records(
+                    &spectra.freqs_mhz,
+                    &[
+                        cross_pols.xy_db.view(),
+                        cross_pols.xy_phase_deg.view(),
+                        cross_pols.yx_db.view(),
+                        cross_pols.yx_phase_deg.view(),
+                    ],
+                ),
+            )?;
+        }
+
+        if let Some(waterfall) = &spectra.waterfall {
+            let (num_ants, num_timesteps, num_fine_chans) = waterfall.xx_db.dim();
+            let records = Array::from_shape_fn(
+                (num_ants, num_timesteps, num_fine_chans, 3),
+                |(ant, timestep, fine_chan, value)| match value {
+                    0 => spectra.freqs_mhz[fine_chan],
+                    1 => waterfall.xx_db[[ant, timestep, fine_chan]],
+                    _ => waterfall.yy_db[[ant, timestep, fine_chan]],
+                },
+            );
+
+            write_npy_file(
+                &self.output_dir.join(format!(
+                    "{}_autos_waterfall_{}chans_{}T_{}ts_ch{}.npy",
+                    context.metafits_context.obs_id,
+                    num_fine_chans,
+                    num_ants,
+                    num_timesteps,
+                    rec_chan_number
+                )),
+                &records,
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+impl FringesWriter for NpyWriter<'_> {
+    fn write_fringes(&mut self, fringes: &FringeSet) -> Result<(), MwaxStatsError> {
+        let context = self.context;
+        let filename = self.output_dir.join(format!(
+            "{}_fringes_{}{}chans_{}T_ch{}.npy",
+            context.metafits_context.obs_id,
+            match self.record_version {
+                FringeRecordVersion::V1 => "",
+                FringeRecordVersion::V2 => "v2_",
+                FringeRecordVersion::V3 => "v3_",
+            },
+            context.metafits_context.num_corr_fine_chans_per_coarse,
+            context.metafits_context.num_ants,
+            context.coarse_chans[fringes.coarse_chan_index].rec_chan_number
+        ));
+
+        let records = fringes_records(fringes, self.record_version)
+            .map_err(|e| MwaxStatsError::write_file(&filename, e))?;
+        write_npy_file(&filename, &records)
+    }
+}
+
+/// Collects the autos and fringes of every coarse channel and writes them to a single OBSID_stats.npz file in `finish`.
+/// The coarse channels are joined along the frequency axis, and the frequency and antenna axes are included:
+///
+///     antennas             [ant] the tile names
+///     baselines            [baseline, 2] the antenna indices of each baseline (u32)
+///     autos_freqs_mhz      [freq]
+///     autos                [ant, freq, 2] XX and YY pow (dB)
+///     autos_stats          [ant, freq, 6] XX min, max and stddev, YY min, max and stddev pow (dB)
+///     autos_crosspol       [ant, freq, 4] XY pow (dB), XY phase (deg), YX pow (dB), YX phase (deg)
+///     autos_waterfall      [ant, timestep, freq, 2] XX and YY pow (dB)
+///     fringes_freqs_mhz    [freq]
+///     fringes              [baseline, freq, 2|5|9] the fringes records (see `FringesFileWriter`) without the
+///                          frequency, so 2, 5 or 9 values for version 1, 2 or 3
+///
+/// The autos stats, cross pols, waterfall and fringes are only included if they were produced. All of the arrays
+/// except the names are little endian f32 unless noted.
+pub struct NpzWriter<'a> {
+    context: &'a CorrelatorContext,
+    output_dir: PathBuf,
+    record_version: FringeRecordVersion,
+    autos: Vec<AutoSpectra>,
+    fringes: Vec<FringeSet>,
+}
+
+impl<'a> NpzWriter<'a> {
+    pub fn new(
+        context: &'a CorrelatorContext,
+        output_dir: &str,
+        record_version: FringeRecordVersion,
+    ) -> Self {
+        NpzWriter {
+            context,
+            output_dir: PathBuf::from(output_dir),
+            record_version,
+            autos: Vec::new(),
+            fringes: Vec::new(),
+        }
+    }
+
+    /// Writes everything collected so far to the .npz file
+    pub fn finish(self) -> Result<(), MwaxStatsError> {
+        let context = self.context;
+        let filename = self
+            .output_dir
+            .join(format!("{}_stats.npz", context.metafits_context.obs_id));
+
+        let write = || -> io::Result<()> {
+            let mut npz = ZipWriter::new(BufWriter::new(File::create(&filename)?));
+
+            let tile_names: Vec<&str> = context
+                .metafits_context
+                .antennas
+                .iter()
+                .map(|a| a.tile_name.as_str())
+                .collect();
+            write_npz_strings(&mut npz, "antennas", &tile_names)?;
+
+            let baselines =
+                Array2::from_shape_fn((context.metafits_context.num_baselines, 2), |(bl, ant)| {
+                    let baseline = &context.metafits_context.baselines[bl];
+                    [baseline.ant1_index, baseline.ant2_index][ant] as u32
+                });
+            write_npz_array(&mut npz, "baselines", &baselines)?;
+
+            self.write_autos(&mut npz)?;
+            self.write_fringes(&mut npz)?;
+
+            npz.finish()?.flush()
+        };
+
+        write().map_err(|e| MwaxStatsError::write_file(&filename, e))?;
+
+        info!("Done! {} written.", filename.display());
+
+        Ok(())
+    }
+
+    fn write_autos<W: Write + Seek>(&self, npz: &mut ZipWriter<W>) -> io::Result<()> {
+        if self.autos.is_empty() {
+            return Ok(());
+        }
+
+        let freqs_mhz: Vec<f32> = self
+            .autos
+            .iter()
+            .flat_map(|spectra| spectra.freqs_mhz.iter().copied())
+            .collect();
+        write_npz_array(npz, "autos_freqs_mhz", &Array::from(freqs_mhz))?;
+
+        // Stacks the [ant][fine chan] columns of each coarse channel, then joins the coarse channels
+        let join = |columns: &dyn Fn(&AutoSpectra) -> Option<Vec<ArrayView2<f32>>>| {
+            let coarse_chans: Option<Vec<Vec<ArrayView2<f32>>>> =
+                self.autos.iter().map(columns).collect();
+            coarse_chans
+                .map(|coarse_chans| {
+                    let stacked = coarse_chans
+                        .iter()
+                        .map(|c| stack(Axis(2), c))
+                        .collect::<Result<Vec<Array3<f32>>, _>>()?;
+                    concatenate(
+                        Axis(1),
+                        &stacked.iter().map(|a| a.view()).collect::<Vec<_>>(),
+                    )
+                })
+                .transpose()
+                .map_err(shape_error)
+        };
+
+        if let Some(autos) = join(&|s| Some(vec![s.xx_db.view(), s.yy_db.view()]))? {
+            write_npz_array(npz, "autos", &autos)?;
+        }
+
+        if let Some(stats) = join(&|s| {
+            s.stats.as_ref().map(|stats| {
+                vec![
+                    stats.xx_min_db.view(),
+                    stats.xx_max_db.view(),
+                    stats.xx_stddev_db.view(),
+                    stats.yy_min_db.view(),
+                    stats.yy_max_db.view(),
+                    stats.yy_stddev_db.view(),
+                ]
+            })
+        })? {
+            write_npz_array(npz, "autos_stats", &stats)?;
+        }
+
+        if let Some(cross_pols) = join(&|s| {
+            s.cross_pols.as_ref().map(|cross_pols| {
+                vec![
+                    cross_pols.xy_db.view(),
+                    cross_pols.xy_phase_deg.view(),
+                    cross_pols.yx_db.view(),
+                    cross_pols.yx_phase_deg.view(),
+                ]
+            })
+        })? {
+            write_npz_array(npz, "autos_crosspol", &cross_pols)?;
+        }
+
+        let waterfalls: Option<Vec<_>> = self.autos.iter().map(|s| s.waterfall.as_ref()).collect();
+        if let Some(waterfalls) = waterfalls {
+            // The coarse channels can only be joined if they have the same timesteps
+            if waterfalls
+                .iter()
+                .all(|w| w.timestep_indices == waterfalls[0].timestep_indices)
+            {
+                let coarse_chans = waterfalls
+                    .iter()
+                    .map(|w| stack(Axis(3), &[w.xx_db.view(), w.yy_db.view()]))
+                    .collect::<Result<Vec<_>, _>>()
+                    .map_err(shape_error)?;
+                let waterfall = concatenate(
+                    Axis(2),
+                    &coarse_chans.iter().map(|a| a.view()).collect::<Vec<_>>(),
+                )
+                .map_err(shape_error)?;
+                write_npz_array(npz, "autos_waterfall", &waterfall)?;
+            } else {
+                warn!("Not including the autos waterfall in the .npz file as the coarse channels have data for different timesteps.");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn write_fringes<W: Write + Seek>(&self, npz: &mut ZipWriter<W>) -> io::Result<()> {
+        if self.fringes.is_empty() {
+            return Ok(());
+        }
+
+        let freqs_mhz: Vec<f32> = self
+            .fringes
+            .iter()
+            .flat_map(|fringes| fringes.freqs_mhz.iter().copied())
+            .collect();
+        write_npz_array(npz, "fringes_freqs_mhz", &Array::from(freqs_mhz))?;
+
+        // The records without the frequency, joined along the frequency axis
+        let coarse_chans = self
+            .fringes
+            .iter()
+            .map(|fringes| {
+                let records = fringes_records(fringes, self.record_version)?;
+                Ok(records.slice_move(ndarray::s![.., .., 1..]))
+            })
+            .collect::<io::Result<Vec<Array3<f32>>>>()?;
+        let fringes = concatenate(
+            Axis(1),
+            &coarse_chans.iter().map(|a| a.view()).collect::<Vec<_>>(),
+        )
+        .map_err(shape_error)?;
+        write_npz_array(npz, "fringes", &fringes)
+    }
+}
+
+impl AutosWriter for NpzWriter<'_> {
+    fn write_autos(&mut self, spectra: &AutoSpectra) -> Result<(), MwaxStatsError> {
+        self.autos.push(spectra.clone());
+        Ok(())
+    }
+}
+
+impl FringesWriter for NpzWriter<'_> {
+    fn write_fringes(&mut self, fringes: &FringeSet) -> Result<(), MwaxStatsError> {
+        self.fringes.push(fringes.clone());
+        Ok(())
+    }
+}
+
+/// The autos records [ant][fine chan][freq, columns...] from the `columns` (each indexed [ant][fine chan])
+fn autos_records(freqs_mhz: &[f32], columns: &[ArrayView2<f32>]) -> Array3<f32> {
+    let (num_ants, num_fine_chans) = columns[0].dim();
+    Array3::from_shape_fn(
+        (num_ants, num_fine_chans, columns.len() + 1),
+        |(ant, fine_chan, value)| match value {
+            0 => freqs_mhz[fine_chan],
+            _ => columns[value - 1][[ant, fine_chan]],
+        },
+    )
+}
+
+/// The fringes records [baseline][fine chan][record] (see `FringeSet::record`)
+fn fringes_records(
+    fringes: &FringeSet,
+    record_version: FringeRecordVersion,
+) -> io::Result<Array3<f32>> {
+    let (num_baselines, num_fine_chans) = fringes.num_timesteps.dim();
+    let records: Vec<f32> = fringes
+        .num_timesteps
+        .indexed_iter()
+        .flat_map(|((bl, fine_chan), _)| fringes.record(bl, fine_chan, record_version))
+        .collect();
+    let record_len = records.len() / (num_baselines * num_fine_chans).max(1);
+
+    Array3::from_shape_vec((num_baselines, num_fine_chans, record_len), records)
+        .map_err(shape_error)
+}
+
+/// The arrays of the coarse channels (or the records) do not fit together
+fn shape_error(e: ShapeError) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+/// Writes `array` as a .npy file
+fn write_npy_file<D: Dimension>(
+    filename: &Path,
+    array: &Array<f32, D>,
+) -> Result<(), MwaxStatsError> {
+    let write = || -> io::Result<()> {
+        let mut writer = BufWriter::new(File::create(filename)?);
+        write_npy(&mut writer, array)?;
+        writer.flush()
+    };
+
+    write().map_err(|e| MwaxStatsError::write_file(filename, e))?;
+
+    info!("Done! {} written.", filename.display());
+
+    Ok(())
+}
+
+/// Writes `array` in the .npy format (C order, in the default dtype of `T`)
+pub(crate) fn write_npy<T: AutoSerialize + Copy, D: Dimension, W: Write>(
+    writer: W,
+    array: &Array<T, D>,
+) -> io::Result<()> {
+    let shape: Vec<u64> = array.shape().iter().map(|n| *n as u64).collect();
+    let mut npy = WriteOptions::new()
+        .default_dtype()
+        .shape(&shape)
+        .writer(writer)
+        .begin_nd()?;
+    npy.extend(array.iter().copied())?;
+    npy.finish()
+}
+
+/// Adds `array` to the .npz file as `name`
+fn write_npz_array<T: AutoSerialize + Copy, D: Dimension, W: Write + Seek>(
+    npz: &mut ZipWriter<W>,
+    name: &str,
+    array: &Array<T, D>,
+) -> io::Result<()> {
+    npz.start_file(format!("{}.npy", name), npz_file_options())?;
+    write_npy(npz, array)
+}
+
+/// Adds `strings` to the .npz file as `name`, as a numpy unicode array
+fn write_npz_strings<W: Write + Seek>(
+    npz: &mut ZipWriter<W>,
+    name: &str,
+    strings: &[&str],
+) -> io::Result<()> {
+    let max_len = strings.iter().map(|s| s.chars().count()).max().unwrap_or(0);
+    let type_str: TypeStr = format!("<U{}", max_len.max(1)).parse().unwrap();
+
+    npz.start_file(format!("{}.npy", name), npz_file_options())?;
+    let mut npy = WriteOptions::<str>::new()
+        .dtype(DType::Plain(type_str))
+        .shape(&[strings.len() as u64])
+        .writer(npz)
+        .begin_nd()?;
+    for s in strings {
+        npy.push(s)?;
+    }
+    npy.finish()
+}
+
+fn npz_file_options() -> FileOptions {
+    // Large .npz files need zip64
+    FileOptions::default()
+        .compression_method(CompressionMethod::Deflated)
+        .large_file(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use npyz::NpyFile;
+    use std::io::{Cursor, Read};
+
+    #[test]
+    fn test_file_format() {
+        assert_eq!("npz".parse::<FileFormat>(), Ok(FileFormat::Npz));
+        assert!("fits".parse::<FileFormat>().is_err());
+    }
+
+    #[test]
+    fn test_write_npy() {
+        let records = autos_records(
+            &[150.0, 150.01],
+            &[
+                Array2::from_elem((3, 2), 1.0).view(),
+                Array2::from_elem((3, 2), 2.0).view(),
+            ],
+        );
+
+        let mut bytes = Vec::new();
+        write_npy(&mut bytes, &records).unwrap();
+
+        let npy = NpyFile::new(bytes.as_slice()).unwrap();
+        assert_eq!(npy.shape(), &[3, 2, 3]);
+        assert_eq!(npy.dtype(), DType::Plain("<f4".parse().unwrap()));
+        let values: Vec<f32> = npy.into_vec().unwrap();
+        assert_eq!(values[..6], [150.0, 1.0, 2.0, 150.01, 1.0, 2.0]);
+    }
+
+    #[test]
+    fn test_write_npz() {
+        let mut npz = ZipWriter::new(Cursor::new(Vec::new()));
+        write_npz_strings(&mut npz, "antennas", &["Tile011", "LBA1"]).unwrap();
+        write_npz_array(&mut npz, "baselines", &Array2::<u32>::zeros((3, 2))).unwrap();
+        let bytes = npz.finish().unwrap().into_inner();
+
+        let mut archive = zip::ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut read_npy = |name: &str| {
+            let mut npy_bytes = Vec::new();
+            archive
+                .by_name(name)
+                .unwrap()
+                .read_to_end(&mut npy_bytes)
+                .unwrap();
+            NpyFile::new(Cursor::new(npy_bytes)).unwrap()
+        };
+
+        let antennas = read_npy("antennas.npy");
+        assert_eq!(antennas.shape(), &[2]);
+        let names: Vec<String> = antennas.into_vec().unwrap();
+        assert_eq!(names, ["Tile011", "LBA1"]);
+
+        let baselines = read_npy("baselines.npy");
+        assert_eq!(baselines.shape(), &[3, 2]);
+        assert_eq!(baselines.dtype(), DType::Plain("<u4".parse().unwrap()));
+    }
+}
@@ -4,16 +4,18 @@
 
 extern crate file_utils;
 use log::{debug, info, trace};
-use ndarray::{ArrayBase, Dim, OwnedRepr};
+use ndarray::{s, Array2, Array3, ArrayView2, Axis};
 use core::ops::Range;
+use std::mem::size_of;
+use std::path::Path;
 use crate::errors::MwaxStatsError;
 use birli::{
     flag_to_weight_array, flags::get_weight_factor, io::read_mwalib, marlu::{
         constants::{
             MWA_HEIGHT_M, MWA_LAT_RAD, MWA_LONG_RAD,
         },
-        mwalib::CorrelatorContext,
-        LatLngHeight, RADec,
+        mwalib::{CorrelatorContext, MetafitsContext, MwalibError},
+        Complex, LatLngHeight, RADec,
     }, FlagContext, Jones, PreprocessContext, VisSelection
 };
 
@@ -35,81 +37,519 @@ pub fn gigabytes_to_bytes(gigabytes_value: f32) -> usize {
     (gigabytes_value * (1000.0 * 1000.0 * 1000.0)) as usize
 }
 
-/// Get a range of timesteps/coarse channels
-/// Returns a result containing a Range of timestep indices and a Range of Coarse channel indices
-/// Will preferably try to get the common good timesteps/coarse channels. If use_any_timesteps is True it
-/// will defer to common timesteps/coarse channels if no common good exist.
-/// We can limit the memory used too (especially good for testing on a laptop)
-pub fn get_timesteps_coarse_chan_ranges(context: &CorrelatorContext, use_any_timestep: bool, memory_limit_gb: Option<f32>) -> Result<(Range<usize>, Range<usize>), MwaxStatsError> {
-    // Get as many good/common timesteps that can fit into our memory limit    
-    let mut returned_timesteps = if context.num_common_good_timesteps > 0 {
-        *context.common_good_timestep_indices.first().unwrap()..context.common_good_timestep_indices.last().unwrap() + 1
-    } else if use_any_timestep {
-        if context.num_common_timesteps > 0 {
-            *context.common_timestep_indices.first().unwrap()..context.common_timestep_indices.last().unwrap() + 1
+/// How to choose the timesteps and coarse channels to use
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    /// The common good timesteps/coarse channels, i.e. those after the quack time which every provided coarse
+    /// channel has data for
+    CommonGood,
+    /// The common timesteps/coarse channels, i.e. those which every provided coarse channel has data for
+    Common,
+    /// Every provided timestep/coarse channel, even if some coarse channels are missing some timesteps
+    AnyProvided,
+    /// These timestep and coarse channel indices (or every provided one if `None`), which must all have been
+    /// provided. The timesteps and coarse channels are used as ranges, so each list must be contiguous: the indices in
+    /// any gap would be used too.
+    Explicit {
+        timestep_indices: Option<Vec<usize>>,
+        coarse_chan_indices: Option<Vec<usize>>,
+    },
+}
+
+/// The timestep and coarse channel index lists of an observation that a `SelectionPolicy` chooses from
+pub struct AvailableIndices<'a> {
+    pub common_good_timesteps: &'a [usize],
+    pub common_good_coarse_chans: &'a [usize],
+    pub common_timesteps: &'a [usize],
+    pub common_coarse_chans: &'a [usize],
+    pub provided_timesteps: &'a [usize],
+    pub provided_coarse_chans: &'a [usize],
+}
+
+impl<'a> AvailableIndices<'a> {
+    pub fn from_context(context: &'a CorrelatorContext) -> Self {
+        Self {
+            common_good_timesteps: &context.common_good_timestep_indices,
+            common_good_coarse_chans: &context.common_good_coarse_chan_indices,
+            common_timesteps: &context.common_timestep_indices,
+            common_coarse_chans: &context.common_coarse_chan_indices,
+            provided_timesteps: &context.provided_timestep_indices,
+            provided_coarse_chans: &context.provided_coarse_chan_indices,
         }
-        else {
-             return Err(MwaxStatsError::NoCommonGoodTimestepCCFound)
+    }
+}
+
+/// Which of the timesteps chosen by a `SelectionPolicy` to use
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TimestepSelection {
+    /// All of them
+    #[default]
+    All,
+    /// Only the first
+    First,
+    /// Only the middle one (the later of the two if there is an even number)
+    Middle,
+    /// Only the last
+    Last,
+}
+
+impl std::str::FromStr for TimestepSelection {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "all" => Ok(TimestepSelection::All),
+            "first" => Ok(TimestepSelection::First),
+            "middle" => Ok(TimestepSelection::Middle),
+            "last" => Ok(TimestepSelection::Last),
+            _ => Err(format!("'{}' is not one of first, middle, last or all", s)),
         }
-    } else {
-        return Err(MwaxStatsError::NoCommonTimestepCCFound)
-    };
-    debug!("{} Timesteps [{}:{}] selected",returned_timesteps.len(), returned_timesteps.start, returned_timesteps.end);
-    
-    // We pretty much always want as many coarse channels as possible
-    let returned_coarse_chans = if context.num_common_good_timesteps > 0 {
-        *context.common_good_coarse_chan_indices.first().unwrap()..context.common_good_coarse_chan_indices.iter().last().unwrap() + 1
-    } else if use_any_timestep {
-        if context.num_common_timesteps > 0 {
-            *context.common_coarse_chan_indices.first().unwrap()..context.common_coarse_chan_indices.iter().last().unwrap() + 1
+    }
+}
+
+/// Applies a selection `policy` to the `available` indices, and then picks the `timestep_selection` out of the
+/// chosen timesteps.
+/// Returns a result containing the Range of timestep indices and the Range of coarse channel indices spanning the
+/// selected ones, or the reason nothing could be selected. Explicit index lists which are not contiguous are an
+/// `InvalidArgument` error, so the ranges only ever contain the indices asked for.
+pub fn select_timesteps_coarse_chans(
+    policy: &SelectionPolicy,
+    timestep_selection: TimestepSelection,
+    available: &AvailableIndices,
+) -> Result<(Range<usize>, Range<usize>), MwaxStatsError> {
+    // The range spanning a (sorted) index list
+    fn span(indices: &[usize]) -> Option<Range<usize>> {
+        Some(*indices.first()?..indices.last()? + 1)
+    }
+
+    // The indices may have been given in any order, but their span must not include any which were not asked for
+    fn contiguous(indices: &[usize], name: &str) -> Result<Vec<usize>, MwaxStatsError> {
+        let mut sorted = indices.to_vec();
+        sorted.sort_unstable();
+        sorted.dedup();
+
+        match span(&sorted) {
+            Some(range) if range.len() != sorted.len() => Err(MwaxStatsError::InvalidArgument(format!(
+                "The {} indices {:?} are not contiguous, the ones in between would also be used",
+                name, sorted
+            ))),
+            _ => Ok(sorted),
         }
-        else {
-             return Err(MwaxStatsError::NoCommonGoodTimestepCCFound)
+    }
+
+    let (timesteps, coarse_chans, error) = match policy {
+        SelectionPolicy::CommonGood => (
+            available.common_good_timesteps.to_vec(),
+            available.common_good_coarse_chans.to_vec(),
+            MwaxStatsError::NoCommonGoodTimestepCCFound,
+        ),
+        SelectionPolicy::Common => (
+            available.common_timesteps.to_vec(),
+            available.common_coarse_chans.to_vec(),
+            MwaxStatsError::NoCommonTimestepCCFound,
+        ),
+        SelectionPolicy::AnyProvided => (
+            available.provided_timesteps.to_vec(),
+            available.provided_coarse_chans.to_vec(),
+            MwaxStatsError::NoProvidedTimestepCCFound,
+        ),
+        SelectionPolicy::Explicit {
+            timestep_indices,
+            coarse_chan_indices,
+        } => {
+            if let Some(t) = timestep_indices
+                .iter()
+                .flatten()
+                .find(|t| !available.provided_timesteps.contains(t))
+            {
+                return Err(MwaxStatsError::TimestepNotProvided(*t));
+            }
+            if let Some(c) = coarse_chan_indices
+                .iter()
+                .flatten()
+                .find(|c| !available.provided_coarse_chans.contains(c))
+            {
+                return Err(MwaxStatsError::CoarseChanNotProvided(*c));
+            }
+
+            (
+                match timestep_indices {
+                    Some(timestep_indices) => contiguous(timestep_indices, "timestep")?,
+                    None => available.provided_timesteps.to_vec(),
+                },
+                match coarse_chan_indices {
+                    Some(coarse_chan_indices) => contiguous(coarse_chan_indices, "coarse channel")?,
+                    None => available.provided_coarse_chans.to_vec(),
+                },
+                MwaxStatsError::InvalidArgument(
+                    "At least one timestep and one coarse channel must be selected".to_string(),
+                ),
+            )
         }
-    } else {
-        return Err(MwaxStatsError::NoCommonTimestepCCFound)
     };
+
+    let timesteps: Vec<usize> = match timestep_selection {
+        TimestepSelection::All => timesteps,
+        TimestepSelection::First => timesteps.first().copied().into_iter().collect(),
+        TimestepSelection::Middle => timesteps
+            .get(timesteps.len() / 2)
+            .copied()
+            .into_iter()
+            .collect(),
+        TimestepSelection::Last => timesteps.last().copied().into_iter().collect(),
+    };
+
+    match (span(&timesteps), span(&coarse_chans)) {
+        (Some(timestep_range), Some(coarse_chan_range)) => Ok((timestep_range, coarse_chan_range)),
+        _ => Err(error),
+    }
+}
+
+/// Returns the index of the timestep (whether it was provided or not) which contains each of `gps_times_s`.
+pub fn get_timestep_indices_from_gps_times(
+    context: &CorrelatorContext,
+    gps_times_s: &[f64],
+) -> Result<Vec<usize>, MwaxStatsError> {
+    let timestep_gps_times_ms: Vec<u64> = context.timesteps.iter().map(|t| t.gps_time_ms).collect();
+
+    gps_times_s
+        .iter()
+        .map(|gps_time_s| {
+            find_timestep_index(
+                &timestep_gps_times_ms,
+                context.metafits_context.corr_int_time_ms,
+                (gps_time_s * 1000.0).round() as u64,
+            )
+            .ok_or(MwaxStatsError::NoTimestepAtGpsTime(*gps_time_s))
+        })
+        .collect()
+}
+
+/// Finds the timestep (each starting at one of `timestep_gps_times_ms` and lasting `int_time_ms`) containing
+/// `gps_time_ms`
+fn find_timestep_index(
+    timestep_gps_times_ms: &[u64],
+    int_time_ms: u64,
+    gps_time_ms: u64,
+) -> Option<usize> {
+    timestep_gps_times_ms
+        .iter()
+        .position(|start| (*start..start + int_time_ms).contains(&gps_time_ms))
+}
+
+/// Get a range of timesteps/coarse channels
+/// Returns a result containing a Range of timestep indices and a Range of Coarse channel indices, chosen by the
+/// selection `policy` and `timestep_selection` (see `select_timesteps_coarse_chans`).
+/// The memory limit is applied later, when the corrected data is read (see `get_timestep_chunks`).
+pub fn get_timesteps_coarse_chan_ranges(context: &CorrelatorContext, policy: &SelectionPolicy, timestep_selection: TimestepSelection) -> Result<(Range<usize>, Range<usize>), MwaxStatsError> {
+    let (returned_timesteps, returned_coarse_chans) = select_timesteps_coarse_chans(
+        policy,
+        timestep_selection,
+        &AvailableIndices::from_context(context),
+    )?;
+
+    debug!("{} Timesteps [{}:{}] selected ({:?}, {:?})",returned_timesteps.len(), returned_timesteps.start, returned_timesteps.end, policy, timestep_selection);
     debug!("{} Coarse channels: [{}:{}] selected",returned_coarse_chans.len(), returned_coarse_chans.start, returned_coarse_chans.end);
 
-    // Determine the number of timesteps we can fit into memory    
-    if memory_limit_gb.is_some() {
-        let memory_limit_bytes: usize = gigabytes_to_bytes(memory_limit_gb.unwrap());
-        let ts_bytes = context.num_timestep_coarse_chan_bytes * returned_coarse_chans.len();
-        let mwax_num_ts_in_memory: usize = memory_limit_bytes / ts_bytes;
+    Ok((returned_timesteps, returned_coarse_chans))
+}
 
-        debug!("Data selection will use {} GB of memory. Memory limit is {} GB. Number of timesteps that can fit in memory: {}.", bytes_to_gigabytes(ts_bytes * returned_timesteps.len()), memory_limit_gb.unwrap(), mwax_num_ts_in_memory);
+/// Estimate of the memory used to read and correct the data of some coarse channels with birli
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MemoryModel {
+    /// Bytes per timestep: the Jones, weight and flag arrays allocated by `get_corrected_data` (and the RFI flags from
+    /// `rfi::flag_rfi`)
+    pub bytes_per_timestep: usize,
+    /// Bytes needed regardless of the number of timesteps: birli's HDU read buffers (one per coarse channel as they
+    /// are read in parallel) and the time summed visibilities kept while streaming chunks (see `VisSum`)
+    pub overhead_bytes: usize,
+}
 
-        if returned_timesteps.len() > mwax_num_ts_in_memory {
-            // Reduce the number of timesteps        
-            returned_timesteps.end = returned_timesteps.end - (returned_timesteps.len() - mwax_num_ts_in_memory);
+impl MemoryModel {
+    /// The memory model of `get_corrected_data` for `num_coarse_chans` coarse channels of this observation
+    pub fn for_corrected_data(context: &CorrelatorContext, num_coarse_chans: usize) -> Self {
+        let num_visibilities = num_coarse_chans
+            * context.metafits_context.num_corr_fine_chans_per_coarse
+            * context.metafits_context.num_baselines;
 
-            debug!("Selected timesteps would have exceeded memory limit.");
-            debug!("Reducing timesteps to {} Timesteps [{}:{}] ({} GB)", returned_timesteps.len(), returned_timesteps.start, returned_timesteps.end, (returned_timesteps.len() as f32 * bytes_to_gigabytes(ts_bytes)));
+        MemoryModel {
+            bytes_per_timestep: num_visibilities
+                * (size_of::<Jones<f32>>() + size_of::<f32>() + 2 * size_of::<bool>()),
+            overhead_bytes: num_coarse_chans * context.num_timestep_coarse_chan_bytes
+                + num_visibilities
+                    * (size_of::<Jones<f64>>() + size_of::<f64>() + size_of::<u32>()),
         }
     }
 
-    Ok((returned_timesteps, returned_coarse_chans))
+    /// The number of timesteps which fit in `memory_limit_bytes`
+    pub fn max_timesteps(&self, memory_limit_bytes: usize) -> usize {
+        memory_limit_bytes.saturating_sub(self.overhead_bytes) / self.bytes_per_timestep.max(1)
+    }
+}
+
+/// Splits `timestep_range` into consecutive chunks which can each be corrected within `memory_limit_gb` according to
+/// `memory_model`. With no memory limit there is a single chunk.
+///
+/// Returns `MwaxStatsError::MemoryLimitTooSmall` if not even one timestep fits.
+pub fn get_timestep_chunks(
+    timestep_range: &Range<usize>,
+    memory_model: &MemoryModel,
+    memory_limit_gb: Option<f32>,
+) -> Result<Vec<Range<usize>>, MwaxStatsError> {
+    let max_timesteps = match memory_limit_gb {
+        Some(memory_limit_gb) => {
+            let max_timesteps = memory_model.max_timesteps(gigabytes_to_bytes(memory_limit_gb));
+
+            debug!(
+                "Data selection will use {} GB of memory. Memory limit is {} GB. Number of timesteps that can fit in memory: {}.",
+                bytes_to_gigabytes(
+                    memory_model.overhead_bytes
+                        + memory_model.bytes_per_timestep * timestep_range.len()
+                ),
+                memory_limit_gb,
+                max_timesteps
+            );
+
+            if max_timesteps == 0 {
+                return Err(MwaxStatsError::MemoryLimitTooSmall {
+                    memory_limit_gb,
+                    required_gb: bytes_to_gigabytes(
+                        memory_model.overhead_bytes + memory_model.bytes_per_timestep,
+                    ),
+                });
+            }
+            max_timesteps
+        }
+        None => timestep_range.len().max(1),
+    };
+
+    Ok(timestep_range
+        .clone()
+        .step_by(max_timesteps)
+        .map(|start| start..(start + max_timesteps).min(timestep_range.end))
+        .collect())
+}
+
+/// The corrected data of a selection of timesteps and coarse channels, from `get_corrected_data`
+pub struct CorrectedData {
+    /// Visibilities (timestep, fine_chan, baseline)
+    pub jones: Array3<Jones<f32>>,
+    /// The weight of each visibility (timestep, fine_chan, baseline). Flagged visibilities have a weight of 0
+    pub weights: Array3<f32>,
+}
+
+impl CorrectedData {
+    /// The timesteps (counting from the first one in the data) with any visibilities which `VisSum::add` would include.
+    /// Timesteps which were not provided, or are entirely flagged, contribute nothing.
+    pub fn unflagged_timesteps(&self) -> Vec<usize> {
+        self.jones
+            .outer_iter()
+            .zip(self.weights.outer_iter())
+            .enumerate()
+            .filter(|(_, (jones, weights))| {
+                jones
+                    .iter()
+                    .zip(weights.iter())
+                    .any(|(j, w)| *w > 0.0 && !j.any_nan())
+            })
+            .map(|(timestep, _)| timestep)
+            .collect()
+    }
+}
+
+/// Running weighted vector sum over timesteps of the corrected data ([fine chan][baseline]), so products which only
+/// need the time summed/averaged visibilities (fringes, delays and gains) can be built up one chunk of timesteps at a
+/// time. Flagged (zero weight) and non-finite visibilities are not included.
+pub struct VisSum {
+    sum: Array2<Jones<f64>>,
+    weight_sum: Array2<f64>,
+    num_timesteps: Array2<u32>,
+}
+
+impl VisSum {
+    pub fn new(num_fine_chans: usize, num_baselines: usize) -> Self {
+        VisSum {
+            sum: Array2::zeros((num_fine_chans, num_baselines)),
+            weight_sum: Array2::zeros((num_fine_chans, num_baselines)),
+            num_timesteps: Array2::zeros((num_fine_chans, num_baselines)),
+        }
+    }
+
+    /// Adds every timestep of `data` to the sum
+    pub fn add(&mut self, data: &CorrectedData) {
+        for (jones_timestep, weights_timestep) in
+            data.jones.outer_iter().zip(data.weights.outer_iter())
+        {
+            ndarray::Zip::from(&mut self.sum)
+                .and(&mut self.weight_sum)
+                .and(&mut self.num_timesteps)
+                .and(&jones_timestep)
+                .and(&weights_timestep)
+                .for_each(|sum, weight_sum, num_timesteps, j, w| {
+                    if *w > 0.0 && !j.any_nan() {
+                        *sum += Jones::<f64>::from(j) * *w as f64;
+                        *weight_sum += *w as f64;
+                        *num_timesteps += 1;
+                    }
+                });
+        }
+    }
+
+    /// The weighted sum over every timestep added so far ([fine chan][baseline])
+    pub fn sum(&self) -> &Array2<Jones<f64>> {
+        &self.sum
+    }
+
+    /// The sum of the weights of every timestep added so far ([fine chan][baseline])
+    pub fn weight_sum(&self) -> &Array2<f64> {
+        &self.weight_sum
+    }
+
+    /// The number of unflagged timesteps added so far ([fine chan][baseline])
+    pub fn num_timesteps(&self) -> &Array2<u32> {
+        &self.num_timesteps
+    }
+
+    /// The weighted vector average over every timestep added so far ([fine chan][baseline]). Fully flagged
+    /// visibilities are NaN.
+    pub fn average(&self) -> Array2<Jones<f32>> {
+        ndarray::Zip::from(&self.sum)
+            .and(&self.weight_sum)
+            .map_collect(|sum, weight_sum| {
+                if *weight_sum > 0.0 {
+                    Jones::<f32>::from(*sum / *weight_sum)
+                } else {
+                    Jones::from([Complex::new(f32::NAN, f32::NAN); 4])
+                }
+            })
+    }
+}
+
+/// How a correction was chosen on the command line
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CorrectionMode {
+    /// Apply it if the metafits says the correlator has not already applied it
+    #[default]
+    Auto,
+    /// Always apply it
+    On,
+    /// Never apply it
+    Off,
+}
+
+impl CorrectionMode {
+    /// Whether to apply the correction, given whether it would be applied in `Auto` mode
+    pub fn resolve(self, auto: bool) -> bool {
+        match self {
+            CorrectionMode::Auto => auto,
+            CorrectionMode::On => true,
+            CorrectionMode::Off => false,
+        }
+    }
+}
+
+impl std::str::FromStr for CorrectionMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(CorrectionMode::Auto),
+            "on" => Ok(CorrectionMode::On),
+            "off" => Ok(CorrectionMode::Off),
+            _ => Err(format!("'{}' is not one of auto, on or off", s)),
+        }
+    }
+}
+
+/// The corrections birli applies in `get_corrected_data`
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Corrections {
+    pub van_vleck: bool,
+    pub cable_lengths: bool,
+    pub digital_gains: bool,
+    pub passband_gains: bool,
+    pub geometry: bool,
+}
+
+impl Corrections {
+    /// The corrections the correlator has not already applied according to the metafits (digital gains are always
+    /// corrected). Van Vleck is never applied by the correlator, so is up to the caller.
+    pub fn auto(metafits_context: &MetafitsContext, van_vleck: bool) -> Self {
+        Corrections {
+            van_vleck,
+            cable_lengths: metafits_context.cable_delays_applied
+                == mwalib::CableDelaysApplied::NoCableDelaysApplied,
+            digital_gains: true,
+            passband_gains: !metafits_context.deripple_applied,
+            geometry: metafits_context.geometric_delays_applied
+                == mwalib::GeometricDelaysApplied::No,
+        }
+    }
+
+    /// The name and value of each correction, for logging and output metadata
+    pub fn named_values(&self) -> Vec<(&'static str, bool)> {
+        vec![
+            ("van_vleck", self.van_vleck),
+            ("cable_lengths", self.cable_lengths),
+            ("digital_gains", self.digital_gains),
+            ("passband_gains", self.passband_gains),
+            ("geometry", self.geometry),
+        ]
+    }
+}
+
+/// Writes the `corrections` used for the coarse channels in `coarse_chan_range` to a text file of `name = value`
+/// lines, named OBSID_corrections_chFIRST-LAST.txt (FIRST and LAST are the first and last receiver channel numbers),
+/// so the corrected products (fringes etc.) can be traced back to them.
+pub fn output_corrections(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    corrections: &Corrections,
+    coarse_chan_range: &Range<usize>,
+) -> Result<(), MwaxStatsError> {
+    let output_filename = Path::new(output_dir).join(format!(
+        "{}_corrections_ch{}-{}.txt",
+        context.metafits_context.obs_id,
+        context.coarse_chans[coarse_chan_range.start].rec_chan_number,
+        context.coarse_chans[coarse_chan_range.end - 1].rec_chan_number
+    ));
+
+    let contents: String = corrections
+        .named_values()
+        .iter()
+        .map(|(name, value)| format!("{} = {}\n", name, value))
+        .collect();
+
+    std::fs::write(&output_filename, contents)
+        .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
 }
 
 ///
-/// Given a CorrelatorContext and timestep and coarse channel range, along with correction flags, performs the corrections on the data and returns a Jones matrix
+/// Given a CorrelatorContext and timestep and coarse channel range, along with the corrections to apply, performs the corrections on the data and returns a Jones matrix and weights
+/// If calsols ([tile][fine chan] for all coarse channels, see `solutions::read_calsols`, which checks they match the observation) are provided they are applied too.
+/// If a phase centre is provided the geometric correction phases the data to it instead of the metafits phase centre.
 ///
 pub fn get_corrected_data(
     context: &CorrelatorContext,
     timestep_range: &Range<usize>,
     coarse_chan_range: &Range<usize>,
-    correct_cable_lengths: bool,
-    correct_digital_gains: bool,
-    correct_passband_gains: bool,
-    correct_geometry: bool,    
-) -> ArrayBase<OwnedRepr<Jones<f32>>, Dim<[usize; 3]>> {
+    corrections: &Corrections,
+    calsols: Option<&Array2<Jones<f64>>>,
+    phase_centre: Option<&RADec>,
+) -> Result<CorrectedData, MwaxStatsError> {
     info!("Correcting data for {} timesteps and {} coarse channels",timestep_range.len(),  coarse_chan_range.len());
 
     // Determine which timesteps and coarse channels we want to use
-    let mut vis_sel = VisSelection::from_mwalib(context).unwrap();
+    let mut vis_sel = VisSelection::from_mwalib(context)
+        .map_err(|e| MwaxStatsError::Preprocess(e.into()))?;
 
-    // Override the timesteps because we only want our single timestep
+    // Override the timesteps and coarse channels with the ones we selected
     vis_sel.timestep_range = timestep_range.clone();
+    vis_sel.coarse_chan_range = coarse_chan_range.clone();
 
     // Get number of fine chans
     let fine_chans_per_coarse = context.metafits_context.num_corr_fine_chans_per_coarse;
@@ -118,7 +558,9 @@ pub fn get_corrected_data(
     let flag_ctx = FlagContext::from_mwalib(context);
 
     // Allocate flags array
-    let mut flag_array = vis_sel.allocate_flags(fine_chans_per_coarse).unwrap();
+    let mut flag_array = vis_sel
+        .allocate_flags(fine_chans_per_coarse)
+        .map_err(|e| MwaxStatsError::Preprocess(e.into()))?;
 
     // Set the flags
     flag_ctx
@@ -128,10 +570,12 @@ pub fn get_corrected_data(
             &vis_sel.coarse_chan_range,
             &vis_sel.get_ant_pairs(&context.metafits_context),
         )
-        .unwrap();
+        .map_err(MwaxStatsError::Preprocess)?;
 
     // Allocate jones array
-    let mut jones_array = vis_sel.allocate_jones(fine_chans_per_coarse).unwrap();
+    let mut jones_array = vis_sel
+        .allocate_jones(fine_chans_per_coarse)
+        .map_err(|e| MwaxStatsError::Preprocess(e.into()))?;
     
     // read visibilities out of the gpubox files
     info!("Reading visibilities");
@@ -142,7 +586,7 @@ pub fn get_corrected_data(
         flag_array.view_mut(),
         false,
     )
-    .unwrap();
+    .map_err(|e| MwaxStatsError::ReadData(e.into()))?;
 
     debug!(
         "Jones array shape (timesteps, fine_chans, baselines){:?}",
@@ -153,21 +597,34 @@ pub fn get_corrected_data(
     let weight_factor = get_weight_factor(context);
     let mut weight_array = flag_to_weight_array(flag_array.view(), weight_factor);
 
+    // Select the calibration solutions for our coarse channels
+    let calsols = calsols.map(|calsols| {
+        let calsol_fine_chans_per_coarse = calsols.dim().1 / context.num_coarse_chans;
+        calsols
+            .slice(s![
+                ..,
+                coarse_chan_range.start * calsol_fine_chans_per_coarse
+                    ..coarse_chan_range.end * calsol_fine_chans_per_coarse
+            ])
+            .to_owned()
+    });
+
     let prep_ctx = PreprocessContext {
-        //Uncomment this is we need to do flagging, but I don't think we do
-        //aoflagger_strategy: match perform_rfi_flagging { true => Some("/usr/share/aoflagger/strategies/mwa-default.lua".to_string()), false=>None} ,
+        // RFI flagging is done afterwards (optionally) by rfi::flag_rfi, so aoflagger is not needed
         array_pos: LatLngHeight {
             longitude_rad: MWA_LONG_RAD,
             latitude_rad: MWA_LAT_RAD,
             height_metres: MWA_HEIGHT_M,
         },
-        phase_centre: RADec::from_mwalib_phase_or_pointing(&context.metafits_context),
-        correct_van_vleck: false,
-        correct_cable_lengths,
-        correct_digital_gains,
-        correct_geometry,
+        phase_centre: phase_centre
+            .copied()
+            .unwrap_or_else(|| RADec::from_mwalib_phase_or_pointing(&context.metafits_context)),
+        correct_van_vleck: corrections.van_vleck,
+        correct_cable_lengths: corrections.cable_lengths,
+        correct_digital_gains: corrections.digital_gains,
+        correct_geometry: corrections.geometry,
         draw_progress: false,
-        passband_gains: match correct_passband_gains {
+        passband_gains: match corrections.passband_gains {
             true => {
                         match context.metafits_context.oversampled {
                             true => Some(birli::passband_gains::OSPFB_JAKE_2025_200HZ),
@@ -176,7 +633,7 @@ pub fn get_corrected_data(
                     },
             _ => None
         },
-        calsols: None,        
+        calsols,
     };
 
     prep_ctx
@@ -187,11 +644,33 @@ pub fn get_corrected_data(
             flag_array.view_mut(),
             &vis_sel,
         )
-        .unwrap();
+        .map_err(MwaxStatsError::Preprocess)?;
 
     info!("Corrections complete");
-    
-    jones_array
+
+    // Flagged visibilities (including the flagged tiles from the metafits) have negative weights, make them 0
+    weight_array.zip_mut_with(&flag_array, |weight, flag| {
+        if *flag || *weight < 0.0 {
+            *weight = 0.0;
+        }
+    });
+
+    Ok(CorrectedData {
+        jones: jones_array,
+        weights: weight_array,
+    })
+}
+
+/// Returns true if the gpubox files provided contain data for this timestep and coarse channel.
+pub fn has_data(
+    context: &CorrelatorContext,
+    timestep_index: usize,
+    coarse_chan_index: usize,
+) -> bool {
+    context
+        .gpubox_time_map
+        .get(&context.timesteps[timestep_index].unix_time_ms)
+        .is_some_and(|t| t.contains_key(&context.coarse_chans[coarse_chan_index].gpubox_number))
 }
 
 /// Given a correlator context, read the timestep of the coarse channel provided.
@@ -199,7 +678,7 @@ pub fn get_data(
     context: &CorrelatorContext,
     timestep_index: usize,
     coarse_chan_index: usize,
-) -> Vec<f32> {
+) -> Result<Vec<f32>, MwaxStatsError> {
     // Get the data for the timestep and coarse channel passed in
     info!(
         "Reading data from timestep index: {} GPS Time: {} / coarse channel index: {} rec_chan: {}...",
@@ -226,22 +705,63 @@ pub fn get_data(
             coarse_chan_index,
             &mut data,
         )
-        .expect("Failed to read data by baseline into buffer");
+        .map_err(|e| MwaxStatsError::ReadData(MwalibError::from(e).into()))?;
     debug!(
         "{} bytes read for coarse channel {}",
         &context.num_timestep_coarse_chan_bytes, coarse_chan_index
     );
 
-    data
+    Ok(data)
+}
+
+/// Reads the data for one timestep and coarse channel like `get_data`, but through birli with only the Van Vleck
+/// correction applied (so the power levels of the autos are comparable between observations).
+pub fn get_van_vleck_corrected_data(
+    context: &CorrelatorContext,
+    timestep_index: usize,
+    coarse_chan_index: usize,
+) -> Result<Vec<f32>, MwaxStatsError> {
+    let corrected_data = get_corrected_data(
+        context,
+        &(timestep_index..timestep_index + 1),
+        &(coarse_chan_index..coarse_chan_index + 1),
+        &Corrections {
+            van_vleck: true,
+            ..Default::default()
+        },
+        None,
+        None,
+    )?;
+
+    Ok(jones_to_mwalib_order(
+        corrected_data.jones.index_axis(Axis(0), 0),
+    ))
+}
+
+/// Converts one timestep of a Jones array (fine_chan, baseline) to the same order as mwalib's
+/// `read_by_baseline_into_buffer`: [baseline][fine chan][pol][r/i]
+fn jones_to_mwalib_order(jones: ArrayView2<Jones<f32>>) -> Vec<f32> {
+    jones
+        .t()
+        .iter()
+        .flat_map(|j| j.iter().flat_map(|c| [c.re, c.im]).collect::<Vec<f32>>())
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     use birli::CorrelatorContext;
 
+    use crate::errors::MwaxStatsError;
     use crate::processing::{bytes_to_gigabytes, gigabytes_to_bytes};
 
-    use super::get_timesteps_coarse_chan_ranges;
+    use super::{
+        find_timestep_index, get_timestep_chunks, get_timesteps_coarse_chan_ranges,
+        jones_to_mwalib_order, select_timesteps_coarse_chans, AvailableIndices, CorrectedData,
+        CorrectionMode, MemoryModel, SelectionPolicy, TimestepSelection, VisSum,
+    };
+    use birli::{marlu::Complex, Jones};
+    use ndarray::Array3;
 
     const TEST_METAFITS_FILENAME: &str = "test_files/1244973688_1_timestep/1244973688.metafits";
     const TEST_MWAX_FITS_FILENAME: &str = "test_files/1244973688_1_timestep/1244973688_20190619100110_ch114_000.fits";
@@ -261,11 +781,15 @@ mod tests {
         // unwrap the context
         let context = context_result.unwrap();
 
-        // Now get the ts anc cc ranges- passing use_any_timestep = False
+        // Now get the ts anc cc ranges- using the common good timesteps
         // The example fits file only has 1 timestep and is within the quaktime, so this should fail
         // as there will be no common good timesteps
-        let result1 = get_timesteps_coarse_chan_ranges(&context,false, None);
-        assert!(result1.is_err());        
+        let result1 = get_timesteps_coarse_chan_ranges(
+            &context,
+            &SelectionPolicy::CommonGood,
+            TimestepSelection::All,
+        );
+        assert!(matches!(result1, Err(MwaxStatsError::NoCommonGoodTimestepCCFound)));
     }
 
     #[test]
@@ -278,9 +802,13 @@ mod tests {
         // unwrap the context
         let context = context_result.unwrap();        
 
-        // Now get the ts anc cc ranges- passing use_any_timestep = True
-        // The example fits file only has 1 timestep and is within the quaktime, so this should succeed as we've said to use any (common) timestep        
-        let result = get_timesteps_coarse_chan_ranges(&context,true, None);
+        // Now get the ts anc cc ranges- using the common timesteps
+        // The example fits file only has 1 timestep and is within the quaktime, so this should succeed as we've said to use any (common) timestep
+        let result = get_timesteps_coarse_chan_ranges(
+            &context,
+            &SelectionPolicy::Common,
+            TimestepSelection::All,
+        );
         assert!(result.is_ok());
         let (ts_range, cc_range) = result.unwrap();
 
@@ -294,6 +822,350 @@ mod tests {
         assert_eq!(cc_range.end, 11);
     }
 
+    // Timesteps 0..6 and coarse channels 0..4 were provided, but coarse channel 3 is missing timesteps 4 and 5
+    // and timesteps 0 and 1 are in the quack time
+    const PROVIDED_TIMESTEPS: [usize; 6] = [0, 1, 2, 3, 4, 5];
+    const PROVIDED_COARSE_CHANS: [usize; 4] = [0, 1, 2, 3];
+    const COMMON_TIMESTEPS: [usize; 4] = [0, 1, 2, 3];
+    const COMMON_GOOD_TIMESTEPS: [usize; 2] = [2, 3];
+
+    fn get_available_indices() -> AvailableIndices<'static> {
+        AvailableIndices {
+            common_good_timesteps: &COMMON_GOOD_TIMESTEPS,
+            common_good_coarse_chans: &PROVIDED_COARSE_CHANS,
+            common_timesteps: &COMMON_TIMESTEPS,
+            common_coarse_chans: &PROVIDED_COARSE_CHANS,
+            provided_timesteps: &PROVIDED_TIMESTEPS,
+            provided_coarse_chans: &PROVIDED_COARSE_CHANS,
+        }
+    }
+
+    #[test]
+    fn test_select_common_good() {
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::CommonGood,
+            TimestepSelection::All,
+            &get_available_indices(),
+        );
+        assert_eq!(result.unwrap(), (2..4, 0..4));
+
+        // All of the timesteps are within the quack time
+        let available = AvailableIndices {
+            common_good_timesteps: &[],
+            common_good_coarse_chans: &[],
+            ..get_available_indices()
+        };
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::CommonGood,
+            TimestepSelection::All,
+            &available,
+        );
+        assert!(matches!(
+            result,
+            Err(MwaxStatsError::NoCommonGoodTimestepCCFound)
+        ));
+    }
+
+    #[test]
+    fn test_select_common() {
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::Common,
+            TimestepSelection::All,
+            &get_available_indices(),
+        );
+        assert_eq!(result.unwrap(), (0..4, 0..4));
+
+        // The coarse channels have no timesteps in common
+        let available = AvailableIndices {
+            common_good_timesteps: &[],
+            common_good_coarse_chans: &[],
+            common_timesteps: &[],
+            common_coarse_chans: &[],
+            ..get_available_indices()
+        };
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::Common,
+            TimestepSelection::All,
+            &available,
+        );
+        assert!(matches!(
+            result,
+            Err(MwaxStatsError::NoCommonTimestepCCFound)
+        ));
+    }
+
+    #[test]
+    fn test_select_any_provided() {
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::AnyProvided,
+            TimestepSelection::All,
+            &get_available_indices(),
+        );
+        assert_eq!(result.unwrap(), (0..6, 0..4));
+
+        let available = AvailableIndices {
+            common_good_timesteps: &[],
+            common_good_coarse_chans: &[],
+            common_timesteps: &[],
+            common_coarse_chans: &[],
+            provided_timesteps: &[],
+            provided_coarse_chans: &[],
+        };
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::AnyProvided,
+            TimestepSelection::All,
+            &available,
+        );
+        assert!(matches!(
+            result,
+            Err(MwaxStatsError::NoProvidedTimestepCCFound)
+        ));
+    }
+
+    #[test]
+    fn test_select_explicit() {
+        let explicit = |timestep_indices: Vec<usize>, coarse_chan_indices: Vec<usize>| {
+            select_timesteps_coarse_chans(
+                &SelectionPolicy::Explicit {
+                    timestep_indices: Some(timestep_indices),
+                    coarse_chan_indices: Some(coarse_chan_indices),
+                },
+                TimestepSelection::All,
+                &get_available_indices(),
+            )
+        };
+
+        // The indices don't need to be in order or common
+        assert_eq!(explicit(vec![5, 3, 4, 4], vec![2]).unwrap(), (3..6, 2..3));
+
+        // The gaps in a list are not filled in
+        assert!(matches!(
+            explicit(vec![5, 1], vec![2]),
+            Err(MwaxStatsError::InvalidArgument(_))
+        ));
+        assert!(matches!(
+            explicit(vec![1], vec![0, 3]),
+            Err(MwaxStatsError::InvalidArgument(_))
+        ));
+
+        // Every provided index of the other is used if only one list is given
+        let result = select_timesteps_coarse_chans(
+            &SelectionPolicy::Explicit {
+                timestep_indices: Some(vec![4]),
+                coarse_chan_indices: None,
+            },
+            TimestepSelection::All,
+            &get_available_indices(),
+        );
+        assert_eq!(result.unwrap(), (4..5, 0..4));
+
+        assert!(matches!(
+            explicit(vec![1, 6], vec![2]),
+            Err(MwaxStatsError::TimestepNotProvided(6))
+        ));
+        assert!(matches!(
+            explicit(vec![1], vec![2, 4]),
+            Err(MwaxStatsError::CoarseChanNotProvided(4))
+        ));
+        assert!(matches!(
+            explicit(vec![], vec![2]),
+            Err(MwaxStatsError::InvalidArgument(_))
+        ));
+    }
+
+    #[test]
+    fn test_select_timestep_keywords() {
+        let select = |policy: &SelectionPolicy, timestep_selection: &str| {
+            select_timesteps_coarse_chans(
+                policy,
+                timestep_selection.parse().unwrap(),
+                &get_available_indices(),
+            )
+        };
+
+        // Picked from the common good timesteps 2 and 3
+        assert_eq!(
+            select(&SelectionPolicy::CommonGood, "all").unwrap(),
+            (2..4, 0..4)
+        );
+        assert_eq!(
+            select(&SelectionPolicy::CommonGood, "first").unwrap(),
+            (2..3, 0..4)
+        );
+        assert_eq!(
+            select(&SelectionPolicy::CommonGood, "middle").unwrap(),
+            (3..4, 0..4)
+        );
+        assert_eq!(
+            select(&SelectionPolicy::CommonGood, "last").unwrap(),
+            (3..4, 0..4)
+        );
+
+        // Picked from the provided timesteps 0..6
+        assert_eq!(
+            select(&SelectionPolicy::AnyProvided, "middle").unwrap(),
+            (3..4, 0..4)
+        );
+        assert_eq!(
+            select(&SelectionPolicy::AnyProvided, "last").unwrap(),
+            (5..6, 0..4)
+        );
+
+        // And from explicit timesteps
+        let explicit = SelectionPolicy::Explicit {
+            timestep_indices: Some(vec![4, 2, 3]),
+            coarse_chan_indices: Some(vec![1]),
+        };
+        assert_eq!(select(&explicit, "middle").unwrap(), (3..4, 1..2));
+
+        assert!("penultimate".parse::<TimestepSelection>().is_err());
+    }
+
+    #[test]
+    fn test_correction_mode() {
+        assert_eq!("auto".parse::<CorrectionMode>(), Ok(CorrectionMode::Auto));
+        assert_eq!("on".parse::<CorrectionMode>(), Ok(CorrectionMode::On));
+        assert_eq!("off".parse::<CorrectionMode>(), Ok(CorrectionMode::Off));
+        assert!("yes".parse::<CorrectionMode>().is_err());
+
+        // Only auto follows the metafits
+        assert!(CorrectionMode::Auto.resolve(true));
+        assert!(!CorrectionMode::Auto.resolve(false));
+        assert!(CorrectionMode::On.resolve(false));
+        assert!(!CorrectionMode::Off.resolve(true));
+    }
+
+    #[test]
+    fn test_find_timestep_index() {
+        let timestep_gps_times_ms = [1_000_000_000_000, 1_000_000_002_000, 1_000_000_004_000];
+
+        assert_eq!(
+            find_timestep_index(&timestep_gps_times_ms, 2000, 1_000_000_000_000),
+            Some(0)
+        );
+        assert_eq!(
+            find_timestep_index(&timestep_gps_times_ms, 2000, 1_000_000_003_999),
+            Some(1)
+        );
+        assert_eq!(
+            find_timestep_index(&timestep_gps_times_ms, 2000, 1_000_000_004_500),
+            Some(2)
+        );
+        assert_eq!(
+            find_timestep_index(&timestep_gps_times_ms, 2000, 1_000_000_006_000),
+            None
+        );
+        assert_eq!(
+            find_timestep_index(&timestep_gps_times_ms, 2000, 999_999_999_000),
+            None
+        );
+    }
+
+    #[test]
+    fn test_get_timestep_chunks() {
+        let memory_model = MemoryModel {
+            bytes_per_timestep: 1_000_000_000,
+            overhead_bytes: 500_000_000,
+        };
+
+        // No limit is a single chunk
+        assert_eq!(
+            get_timestep_chunks(&(2..9), &memory_model, None).unwrap(),
+            vec![2..9]
+        );
+
+        // 3 timesteps fit in 3.5 GB
+        assert_eq!(memory_model.max_timesteps(3_500_000_000), 3);
+        assert_eq!(
+            get_timestep_chunks(&(2..9), &memory_model, Some(3.5)).unwrap(),
+            vec![2..5, 5..8, 8..9]
+        );
+        assert_eq!(
+            get_timestep_chunks(&(2..9), &memory_model, Some(100.0)).unwrap(),
+            vec![2..9]
+        );
+
+        // Not even one timestep fits
+        assert!(matches!(
+            get_timestep_chunks(&(2..9), &memory_model, Some(1.4)),
+            Err(MwaxStatsError::MemoryLimitTooSmall { .. })
+        ));
+    }
+
+    #[test]
+    fn test_vis_sum() {
+        let mut data = CorrectedData {
+            jones: Array3::<Jones<f32>>::zeros((3, 3, 1)),
+            weights: Array3::<f32>::ones((3, 3, 1)),
+        };
+        for (timestep, mut j) in data.jones.outer_iter_mut().enumerate() {
+            j[[0, 0]] = Jones::from([Complex::new(timestep as f32, 1.0); 4]);
+            j[[1, 0]] = Jones::from([Complex::new(timestep as f32, 1.0); 4]);
+        }
+        // Fine channel 1 has a flagged timestep and a double weighted one, fine channel 2 is fully flagged
+        data.weights[[0, 1, 0]] = 0.0;
+        data.weights[[2, 1, 0]] = 2.0;
+        data.weights.slice_mut(ndarray::s![.., 2, ..]).fill(0.0);
+
+        // Adding the timesteps in chunks is the same as adding them all at once
+        let mut vis_sum = VisSum::new(3, 1);
+        for chunk in [0..2, 2..3] {
+            vis_sum.add(&CorrectedData {
+                jones: data
+                    .jones
+                    .slice(ndarray::s![chunk.clone(), .., ..])
+                    .to_owned(),
+                weights: data.weights.slice(ndarray::s![chunk, .., ..]).to_owned(),
+            });
+        }
+
+        let mut vis_sum_all = VisSum::new(3, 1);
+        vis_sum_all.add(&data);
+
+        let average = vis_sum.average();
+        assert_eq!(average[[0, 0]], vis_sum_all.average()[[0, 0]]);
+        assert_eq!(average[[0, 0]], Jones::from([Complex::new(1.0, 1.0); 4]));
+        assert_eq!(vis_sum.num_timesteps()[[0, 0]], 3);
+
+        // (1 + 2 * 2) / 3
+        assert_eq!(
+            average[[1, 0]],
+            Jones::from([Complex::new(5.0 / 3.0, 1.0); 4])
+        );
+        assert_eq!(vis_sum.num_timesteps()[[1, 0]], 2);
+
+        assert!(average[[2, 0]].any_nan());
+        assert_eq!(vis_sum.num_timesteps()[[2, 0]], 0);
+
+        // Only the timesteps with unflagged data contribute
+        assert_eq!(data.unflagged_timesteps(), vec![0, 1, 2]);
+        data.weights.slice_mut(ndarray::s![1, .., ..]).fill(0.0);
+        assert_eq!(data.unflagged_timesteps(), vec![0, 2]);
+    }
+
+    #[test]
+    fn test_jones_to_mwalib_order() {
+        let mut jones = ndarray::Array2::<Jones<f32>>::zeros((2, 3));
+        for ((fine_chan, bl), j) in jones.indexed_iter_mut() {
+            let x = (bl * 10 + fine_chan) as f32;
+            *j = Jones::from([
+                Complex::new(x, 0.1),
+                Complex::new(x, 0.2),
+                Complex::new(x, 0.3),
+                Complex::new(x, 0.4),
+            ]);
+        }
+
+        let data = jones_to_mwalib_order(jones.view());
+
+        // [baseline][fine chan][pol][r/i]
+        assert_eq!(data.len(), 3 * 2 * 4 * 2);
+        assert_eq!(data[0..8], [0.0, 0.1, 0.0, 0.2, 0.0, 0.3, 0.0, 0.4]);
+        assert_eq!(data[8], 1.0);
+        assert_eq!(data[16], 10.0);
+        assert_eq!(data[40..42], [21.0, 0.1]);
+    }
+
     #[test]
     fn test_bytes_to_gigabytes() {
         assert_eq!(10.0, bytes_to_gigabytes(10_000_000_000));
@@ -0,0 +1,474 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+//! Readers for the autos, fringes and packet stats files written by `mwax_stats` and `mwax_packet_stats`.
+//!
+//! The dimensions come from the `FileHeader` if the file has one, or otherwise (legacy files) from the filename, using
+//! the conventions in the README.
+use crate::header::{FileHeader, RecordLayout, MAGIC};
+use ndarray::{s, Array1, Array3, Array4};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// What the name of an autos or fringes file says about it
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileName {
+    pub obs_id: u32,
+    pub record_layout: RecordLayout,
+    pub num_fine_chans: usize,
+    pub num_tiles: usize,
+    /// Only waterfall filenames include the number of timesteps
+    pub num_timesteps: Option<usize>,
+    pub rec_chan_number: usize,
+}
+
+impl FileName {
+    /// Parses an autos or fringes filename e.g. 1317706936_autos_64chans_128T_ch123.dat
+    pub fn parse(filename: &str) -> Option<Self> {
+        let mut fields = filename.strip_suffix(".dat")?.split('_');
+
+        let obs_id = fields.next()?.parse().ok()?;
+
+        let mut field = fields.next()?;
+        let record_layout = match (field, fields.clone().next()?) {
+            ("autos", "stats") => RecordLayout::AutosStats,
+            ("autos", "crosspol") => RecordLayout::AutosCrossPols,
+            ("autos", "waterfall") => RecordLayout::AutosWaterfall,
+            ("autos", _) => RecordLayout::Autos,
+            ("fringes", "v2") => RecordLayout::FringesV2,
+            ("fringes", "v3") => RecordLayout::FringesV3,
+            ("fringes", _) => RecordLayout::FringesV1,
+            _ => return None,
+        };
+        field = fields.next()?;
+        if !field.ends_with("chans") {
+            field = fields.next()?;
+        }
+
+        let num_fine_chans = field.strip_suffix("chans")?.parse().ok()?;
+        let num_tiles = fields.next()?.strip_suffix('T')?.parse().ok()?;
+
+        field = fields.next()?;
+        let num_timesteps = match record_layout {
+            RecordLayout::AutosWaterfall => {
+                let num_timesteps = field.strip_suffix("ts")?.parse().ok()?;
+                field = fields.next()?;
+                Some(num_timesteps)
+            }
+            _ => None,
+        };
+
+        let rec_chan_number = field.strip_prefix("ch")?.parse().ok()?;
+
+        match fields.next() {
+            None => Some(FileName {
+                obs_id,
+                record_layout,
+                num_fine_chans,
+                num_tiles,
+                num_timesteps,
+                rec_chan_number,
+            }),
+            Some(_) => None,
+        }
+    }
+
+    fn is_autos(&self) -> bool {
+        matches!(
+            self.record_layout,
+            RecordLayout::Autos
+                | RecordLayout::AutosStats
+                | RecordLayout::AutosCrossPols
+                | RecordLayout::AutosWaterfall
+        )
+    }
+}
+
+/// An autos file: the autos, stats, cross-polarisation or waterfall file of one coarse channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct AutosFile {
+    pub name: FileName,
+    /// None for legacy files
+    pub header: Option<FileHeader>,
+    /// The frequency (MHz) of each fine channel
+    pub freqs_mhz: Array1<f32>,
+    /// The values after the frequency of each record, [ant][timestep][fine chan][value]. There is only one timestep
+    /// unless it is a waterfall, and the values depend on the `RecordLayout` e.g. XX and YY power (dB) for the autos
+    pub values: Array4<f32>,
+}
+
+impl AutosFile {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let (name, header, floats) = read_floats(path)?;
+        if !name.is_autos() {
+            return Err(invalid(path, "not an autos file"));
+        }
+
+        // Only the waterfall has more than one timestep (and the header agrees with the filename)
+        let num_timesteps = name.num_timesteps.unwrap_or(1);
+        let floats_per_record = name.record_layout.floats_per_record() as usize;
+        let shape = (
+            name.num_tiles,
+            num_timesteps,
+            name.num_fine_chans,
+            floats_per_record,
+        );
+        let records = Array4::from_shape_vec(shape, floats)
+            .map_err(|_| invalid(path, "the file size does not match its dimensions"))?;
+
+        Ok(AutosFile {
+            freqs_mhz: records.slice(s![0, 0, .., 0]).to_owned(),
+            values: records.slice(s![.., .., .., 1..]).to_owned(),
+            name,
+            header,
+        })
+    }
+}
+
+/// A fringes file of one coarse channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct FringesFile {
+    pub name: FileName,
+    /// None for legacy files
+    pub header: Option<FileHeader>,
+    /// The frequency (MHz) of each fine channel
+    pub freqs_mhz: Array1<f32>,
+    /// The values after the frequency of each record, [baseline][fine chan][value]. The values depend on the version
+    /// (`RecordLayout`) e.g. XX and YY phase (deg) for version 1
+    pub values: Array3<f32>,
+}
+
+impl FringesFile {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let (name, header, floats) = read_floats(path)?;
+        if name.is_autos() {
+            return Err(invalid(path, "not a fringes file"));
+        }
+
+        let num_baselines = name.num_tiles * (name.num_tiles + 1) / 2;
+        let floats_per_record = name.record_layout.floats_per_record() as usize;
+        let records = Array3::from_shape_vec(
+            (num_baselines, name.num_fine_chans, floats_per_record),
+            floats,
+        )
+        .map_err(|_| invalid(path, "the file size does not match its dimensions"))?;
+
+        Ok(FringesFile {
+            freqs_mhz: records.slice(s![0, .., 0]).to_owned(),
+            values: records.slice(s![.., .., 1..]).to_owned(),
+            name,
+            header,
+        })
+    }
+}
+
+/// A packet stats file written by `mwax_packet_stats` e.g. packetstats_1234567890_128T_ch123_mwax01.dat
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketStatsFile {
+    pub subobs_id: u32,
+    pub num_tiles: usize,
+    pub rec_chan_number: usize,
+    pub hostname: String,
+    /// The number of packets lost by each rfinput (2 per tile, in subfile order)
+    pub packets_lost: Array1<u16>,
+}
+
+impl PacketStatsFile {
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let filename = path
+            .file_name()
+            .and_then(|f| f.to_str())
+            .unwrap_or_default();
+        let parse_name = || -> Option<(u32, usize, usize, String)> {
+            let mut fields = filename
+                .strip_prefix("packetstats_")?
+                .strip_suffix(".dat")?
+                .splitn(4, '_');
+            Some((
+                fields.next()?.parse().ok()?,
+                fields.next()?.strip_suffix('T')?.parse().ok()?,
+                fields.next()?.strip_prefix("ch")?.parse().ok()?,
+                fields.next()?.to_string(),
+            ))
+        };
+        let (subobs_id, num_tiles, rec_chan_number, hostname) =
+            parse_name().ok_or_else(|| invalid(path, "not a packet stats filename"))?;
+
+        let bytes = fs::read(path)?;
+        if bytes.len() != num_tiles * 2 * 2 {
+            return Err(invalid(path, "the file size does not match its dimensions"));
+        }
+
+        Ok(PacketStatsFile {
+            subobs_id,
+            num_tiles,
+            rec_chan_number,
+            hostname,
+            packets_lost: bytes
+                .chunks_exact(2)
+                .map(|b| u16::from_le_bytes([b[0], b[1]]))
+                .collect(),
+        })
+    }
+}
+
+/// Reads the name, header (if any) and the floats of the records of an autos or fringes file, checking that the
+/// header agrees with the name (including the number of timesteps of a waterfall)
+fn read_floats(path: &Path) -> io::Result<(FileName, Option<FileHeader>, Vec<f32>)> {
+    let name = path
+        .file_name()
+        .and_then(|f| f.to_str())
+        .and_then(FileName::parse)
+        .ok_or_else(|| invalid(path, "not an autos or fringes filename"))?;
+
+    let bytes = fs::read(path)?;
+    let mut records = bytes.as_slice();
+
+    let header = if records.starts_with(&MAGIC) {
+        let header = FileHeader::read(&mut records)?;
+        if header.obs_id != name.obs_id
+            || header.record_layout != name.record_layout
+            || header.num_fine_chans as usize != name.num_fine_chans
+            || header.num_tiles as usize != name.num_tiles
+            || header.rec_chan_number as usize != name.rec_chan_number
+            || name
+                .num_timesteps
+                .is_some_and(|num_timesteps| header.num_timesteps as usize != num_timesteps)
+        {
+            return Err(invalid(path, "the header does not match the filename"));
+        }
+        Some(header)
+    } else {
+        None
+    };
+
+    if records.len() % 4 != 0 {
+        return Err(invalid(path, "the file size does not match its dimensions"));
+    }
+
+    let floats = records
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+
+    Ok((name, header, floats))
+}
+
+fn invalid(path: &Path, reason: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("Unable to read {}: {}", path.display(), reason),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::processing::{Corrections, VisSum};
+    use crate::{autos, fringes, subfile};
+    use ndarray::{Array2, Axis};
+    use std::io::Write;
+
+    #[test]
+    fn test_parse_filename() {
+        assert_eq!(
+            FileName::parse("1317706936_autos_waterfall_64chans_128T_14ts_ch123.dat"),
+            Some(FileName {
+                obs_id: 1317706936,
+                record_layout: RecordLayout::AutosWaterfall,
+                num_fine_chans: 64,
+                num_tiles: 128,
+                num_timesteps: Some(14),
+                rec_chan_number: 123,
+            })
+        );
+        assert_eq!(
+            FileName::parse("1317706936_fringes_v3_1chans_4T_ch9.dat").map(|name| (
+                name.record_layout,
+                name.num_fine_chans,
+                name.num_tiles
+            )),
+            Some((RecordLayout::FringesV3, 1, 4))
+        );
+        assert_eq!(
+            FileName::parse("1317706936_autos_crosspol_64chans_128T_ch123.dat")
+                .map(|name| name.record_layout),
+            Some(RecordLayout::AutosCrossPols)
+        );
+        assert!(FileName::parse("1317706936_delays_128T_ch123-124.dat").is_none());
+        assert!(FileName::parse("1317706936_autos_64chans_128T_ch123.txt").is_none());
+    }
+
+    #[test]
+    fn test_read_autos_file() {
+        let (num_tiles, num_timesteps, num_fine_chans) = (3, 2, 4);
+        let freqs_mhz: Vec<f32> = (0..num_fine_chans).map(|c| 150.0 + c as f32).collect();
+        let waterfall = Array4::from_shape_fn(
+            (num_tiles, num_timesteps, num_fine_chans, 2),
+            |(ant, timestep, fine_chan, pol)| {
+                (ant * 1000 + timestep * 100 + fine_chan * 10 + pol) as f32
+            },
+        );
+        let header = FileHeader {
+            start_gps_time_ms: 1317706936000,
+            end_gps_time_ms: 1317706952000,
+            obs_id: 1317706936,
+            rec_chan_number: 123,
+            num_tiles: num_tiles as u32,
+            num_fine_chans: num_fine_chans as u32,
+            num_timesteps: num_timesteps as u32,
+            record_layout: RecordLayout::AutosWaterfall,
+            corrections: Corrections {
+                van_vleck: true,
+                ..Default::default()
+            },
+        };
+
+        let write_waterfall = |path: &Path, header: Option<FileHeader>| {
+            autos::write_autos_waterfall_file(
+                path,
+                header,
+                &freqs_mhz,
+                waterfall.index_axis(Axis(3), 0),
+                waterfall.index_axis(Axis(3), 1),
+            )
+            .unwrap()
+        };
+
+        // Each test has its own directory, so concurrent runs don't write over each other's files
+        let dir = tempfile::tempdir().unwrap();
+
+        // With a header
+        let path = &dir
+            .path()
+            .join("1317706936_autos_waterfall_4chans_3T_2ts_ch123.dat");
+        write_waterfall(path, Some(header));
+
+        let autos_file = AutosFile::read(path).unwrap();
+        assert_eq!(autos_file.header, Some(header));
+        assert_eq!(autos_file.freqs_mhz.to_vec(), freqs_mhz);
+        assert_eq!(autos_file.values, waterfall);
+
+        // Legacy, the dimensions come from the filename
+        write_waterfall(path, None);
+
+        let autos_file = AutosFile::read(path).unwrap();
+        assert_eq!(autos_file.header, None);
+        assert_eq!(autos_file.values, waterfall);
+
+        // Stats: [ant][fine chan] columns
+        let columns: Vec<Array2<f32>> = (0..6)
+            .map(|column| {
+                Array2::from_shape_fn((num_tiles, num_fine_chans), |(ant, fine_chan)| {
+                    (column * 100 + ant * 10 + fine_chan) as f32
+                })
+            })
+            .collect();
+        let path = &dir
+            .path()
+            .join("1317706936_autos_stats_4chans_3T_ch123.dat");
+        autos::write_autos_file(
+            path,
+            None,
+            &freqs_mhz,
+            &columns.iter().map(|c| c.view()).collect::<Vec<_>>(),
+        )
+        .unwrap();
+
+        let autos_file = AutosFile::read(path).unwrap();
+        assert_eq!(autos_file.values.dim(), (num_tiles, 1, num_fine_chans, 6));
+        assert_eq!(autos_file.values[[2, 0, 3, 5]], columns[5][[2, 3]]);
+
+        // The header must match the filename
+        let path = &dir
+            .path()
+            .join("1317706936_autos_waterfall_4chans_3T_2ts_ch124.dat");
+        write_waterfall(path, Some(header));
+        assert!(AutosFile::read(path).is_err());
+
+        // Including the number of timesteps of the waterfall, even when the records fit the header
+        let path = &dir
+            .path()
+            .join("1317706936_autos_waterfall_4chans_3T_1ts_ch123.dat");
+        write_waterfall(path, Some(header));
+        assert_eq!(
+            AutosFile::read(path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+        let path = &dir
+            .path()
+            .join("1317706936_autos_waterfall_4chans_3T_2ts_ch123.dat");
+        let one_timestep = FileHeader {
+            num_timesteps: 1,
+            ..header
+        };
+        write_waterfall(path, Some(one_timestep));
+        assert!(AutosFile::read(path)
+            .unwrap_err()
+            .to_string()
+            .contains("the header does not match the filename"));
+    }
+
+    #[test]
+    fn test_read_fringes_file() {
+        // Nothing summed for 3 baselines and 2 fine channels
+        let fringe_set = fringes::FringeSet::from_vis_sum(
+            &VisSum::new(2, 3),
+            0,
+            0,
+            vec![0, 1],
+            Corrections::default(),
+            vec![150.0, 150.01],
+        );
+
+        let header = FileHeader {
+            start_gps_time_ms: 1317706936000,
+            end_gps_time_ms: 1317706952000,
+            obs_id: 1317706936,
+            rec_chan_number: 123,
+            num_tiles: 2,
+            num_fine_chans: 2,
+            num_timesteps: 2,
+            record_layout: RecordLayout::FringesV2,
+            corrections: Corrections::default(),
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir.path().join("1317706936_fringes_v2_2chans_2T_ch123.dat");
+        let mut file = fs::File::create(path).unwrap();
+        header.write(&mut file).unwrap();
+        fringes::write_fringes_records(&mut file, &fringe_set, fringes::FringeRecordVersion::V2)
+            .unwrap();
+        file.flush().unwrap();
+
+        let fringes_file = FringesFile::read(path).unwrap();
+        assert_eq!(fringes_file.header, Some(header));
+        assert_eq!(fringes_file.freqs_mhz.to_vec(), vec![150.0, 150.01]);
+        assert_eq!(fringes_file.values.dim(), (3, 2, 5));
+        // Nothing was summed, so the phases and amplitudes are NaN and the number of timesteps is 0
+        assert!(fringes_file.values[[1, 0, 0]].is_nan());
+        assert_eq!(fringes_file.values[[1, 0, 4]], 0.0);
+
+        // A legacy file which is not the size the filename says
+        let path = &dir.path().join("1317706936_fringes_4chans_2T_ch123.dat");
+        fs::write(path, [0u8; 3 * 2 * 3 * 4]).unwrap();
+        assert!(FringesFile::read(path).is_err());
+    }
+
+    #[test]
+    fn test_read_packet_stats_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = &dir
+            .path()
+            .join("packetstats_1234567890_2T_ch123_mwax01.dat");
+        subfile::write_packet_stats(&[8, 2098, 0, 65535], path).unwrap();
+
+        let packet_stats = PacketStatsFile::read(path).unwrap();
+        assert_eq!(packet_stats.subobs_id, 1234567890);
+        assert_eq!(packet_stats.num_tiles, 2);
+        assert_eq!(packet_stats.rec_chan_number, 123);
+        assert_eq!(packet_stats.hostname, "mwax01");
+        assert_eq!(packet_stats.packets_lost.to_vec(), vec![8, 2098, 0, 65535]);
+    }
+}
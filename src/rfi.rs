@@ -0,0 +1,473 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use crate::processing::CorrectedData;
+use core::ops::Range;
+use log::{debug, info, trace};
+use mwalib::{Baseline, CorrelatorContext};
+use ndarray::{s, Array1, Array3, ArrayView1, ArrayViewMut1, Axis, Zip};
+use rayon::prelude::*;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// SumThreshold's threshold for a single sample, in units of the (robust) standard deviation of the data
+const BASE_THRESHOLD_SIGMA: f32 = 6.0;
+/// How much the threshold drops each time the window size doubles (as in AOFlagger)
+const THRESHOLD_RHO: f32 = 1.5;
+/// The window sizes (in fine channels) SumThreshold is run with, along frequency
+const WINDOW_SIZES: [usize; 6] = [1, 2, 4, 8, 16, 32];
+/// Converts the median absolute deviation to the standard deviation of Gaussian noise
+const MAD_TO_SIGMA: f32 = 1.4826;
+
+/// Flags RFI in `data` with the SumThreshold algorithm (Offringa et al. 2010) and sets the weight of the flagged
+/// visibilities to 0. This is a simplified, pure Rust version of what AOFlagger does, so it does not need to be
+/// installed.
+///
+/// Each baseline is flagged independently on the amplitudes of its XX and YY visibilities of each timestep, along
+/// frequency only, so the flags do not depend on which other timesteps are in `data` (e.g. the chunk size). The
+/// background is the median of each coarse channel (of `fine_chans_per_coarse` fine channels), as the passband has
+/// been corrected, and the threshold is scaled by the median absolute deviation from it. Only excess power is flagged.
+///
+/// As there is no pass along time, RFI covering a whole coarse channel of a timestep raises the background with it so
+/// is not flagged.
+///
+/// Returns the RFI flags (timestep, fine_chan, baseline). Visibilities which were already flagged are not included.
+pub fn flag_rfi(data: &mut CorrectedData, fine_chans_per_coarse: usize) -> Array3<bool> {
+    info!("Flagging RFI");
+
+    let mut rfi_flags = Array3::<bool>::from_elem(data.jones.dim(), false);
+
+    // Flag each baseline in parallel
+    rfi_flags
+        .axis_iter_mut(Axis(2))
+        .into_par_iter()
+        .zip(data.weights.axis_iter_mut(Axis(2)))
+        .zip(data.jones.axis_iter(Axis(2)))
+        .for_each(|((mut bl_rfi_flags, mut bl_weights), bl_jones)| {
+            let flagged = Zip::from(&bl_jones)
+                .and(&bl_weights)
+                .map_collect(|j, w| j.any_nan() || *w <= 0.0);
+
+            let mut flags = flagged.clone();
+            for pol in [0, 3] {
+                for ((jones_row, flagged_row), mut flags_row) in bl_jones
+                    .outer_iter()
+                    .zip(flagged.outer_iter())
+                    .zip(flags.outer_iter_mut())
+                {
+                    let amps = jones_row.map(|j| j[pol].norm());
+                    let pol_flags = sum_threshold(amps.view(), flagged_row, fine_chans_per_coarse);
+                    Zip::from(&mut flags_row)
+                        .and(&pol_flags)
+                        .for_each(|flag, pol_flag| *flag |= *pol_flag);
+                }
+            }
+
+            Zip::from(&mut bl_rfi_flags)
+                .and(&mut bl_weights)
+                .and(&flags)
+                .and(&flagged)
+                .for_each(|rfi_flag, weight, flag, already_flagged| {
+                    if *flag && !*already_flagged {
+                        *rfi_flag = true;
+                        *weight = 0.0;
+                    }
+                });
+        });
+
+    debug!(
+        "{} of {} visibilities flagged as RFI",
+        rfi_flags.iter().filter(|f| **f).count(),
+        rfi_flags.len()
+    );
+
+    rfi_flags
+}
+
+/// Runs SumThreshold over frequency on the `amps` (fine chan) of one timestep of one baseline/polarisation. Returns
+/// the flags, including the already `flagged` samples.
+fn sum_threshold(
+    amps: ArrayView1<f32>,
+    flagged: ArrayView1<bool>,
+    fine_chans_per_coarse: usize,
+) -> Array1<bool> {
+    let fine_chans_per_coarse = fine_chans_per_coarse.max(1);
+
+    // Subtract the background of each coarse channel
+    let mut residuals = Array1::<f32>::zeros(amps.len());
+    for start in (0..amps.len()).step_by(fine_chans_per_coarse) {
+        let fine_chans = start..(start + fine_chans_per_coarse).min(amps.len());
+
+        let background = median(
+            fine_chans
+                .clone()
+                .filter(|c| !flagged[*c])
+                .map(|c| amps[c])
+                .collect(),
+        )
+        .unwrap_or_default();
+
+        for c in fine_chans {
+            residuals[c] = amps[c] - background;
+        }
+    }
+
+    let mut flags = flagged.to_owned();
+
+    // Scale the threshold by the noise
+    let sigma = match median(
+        residuals
+            .iter()
+            .zip(flagged.iter())
+            .filter(|(_, f)| !**f)
+            .map(|(r, _)| r.abs())
+            .collect(),
+    ) {
+        Some(mad) if mad > 0.0 => mad * MAD_TO_SIGMA,
+        _ => return flags,
+    };
+
+    for window in WINDOW_SIZES {
+        let threshold = BASE_THRESHOLD_SIGMA * sigma / THRESHOLD_RHO.powf((window as f32).log2());
+
+        let mut new_flags = flags.clone();
+        sum_threshold_1d(
+            residuals.view(),
+            flags.view(),
+            window,
+            threshold,
+            new_flags.view_mut(),
+        );
+        flags = new_flags;
+    }
+
+    flags
+}
+
+/// One SumThreshold pass over a line of `residuals`: each `window` consecutive samples whose mean exceeds `threshold`
+/// are flagged in `new_flags`. Already flagged samples count as the threshold (as in AOFlagger).
+fn sum_threshold_1d(
+    residuals: ArrayView1<f32>,
+    flags: ArrayView1<bool>,
+    window: usize,
+    threshold: f32,
+    mut new_flags: ArrayViewMut1<bool>,
+) {
+    if residuals.len() < window {
+        return;
+    }
+
+    let value = |i: usize| if flags[i] { threshold } else { residuals[i] };
+
+    let mut sum: f32 = (0..window).map(value).sum();
+    for start in 0..=residuals.len() - window {
+        if start > 0 {
+            sum += value(start + window - 1) - value(start - 1);
+        }
+
+        if sum > threshold * window as f32 {
+            new_flags.slice_mut(s![start..start + window]).fill(true);
+        }
+    }
+}
+
+/// The median of `values`, or None if there are none
+fn median(mut values: Vec<f32>) -> Option<f32> {
+    if values.is_empty() {
+        return None;
+    }
+    let middle = values.len() / 2;
+    let (_, median, _) = values.select_nth_unstable_by(middle, |a, b| a.total_cmp(b));
+    Some(*median)
+}
+
+/// Running count of the RFI flags of each fine channel and antenna, so the occupancy can be built up one chunk of
+/// timesteps at a time.
+pub struct FlagOccupancy {
+    fine_chan_flagged: Vec<u64>,
+    fine_chan_total: Vec<u64>,
+    ant_flagged: Vec<u64>,
+    ant_total: Vec<u64>,
+}
+
+impl FlagOccupancy {
+    pub fn new(num_fine_chans: usize, num_ants: usize) -> Self {
+        FlagOccupancy {
+            fine_chan_flagged: vec![0; num_fine_chans],
+            fine_chan_total: vec![0; num_fine_chans],
+            ant_flagged: vec![0; num_ants],
+            ant_total: vec![0; num_ants],
+        }
+    }
+
+    /// Counts the `rfi_flags` (from `flag_rfi`) of `data`. Visibilities which were flagged before RFI flagging are
+    /// not counted at all.
+    pub fn add(&mut self, data: &CorrectedData, rfi_flags: &Array3<bool>, baselines: &[Baseline]) {
+        Zip::indexed(rfi_flags).and(&data.weights).for_each(
+            |(_, fine_chan, bl_index), rfi_flag, weight| {
+                if !*rfi_flag && *weight <= 0.0 {
+                    return;
+                }
+
+                let bl = &baselines[bl_index];
+                let ants = if bl.ant1_index == bl.ant2_index {
+                    vec![bl.ant1_index]
+                } else {
+                    vec![bl.ant1_index, bl.ant2_index]
+                };
+
+                self.fine_chan_total[fine_chan] += 1;
+                for ant in &ants {
+                    self.ant_total[*ant] += 1;
+                }
+
+                if *rfi_flag {
+                    self.fine_chan_flagged[fine_chan] += 1;
+                    for ant in &ants {
+                        self.ant_flagged[*ant] += 1;
+                    }
+                }
+            },
+        );
+    }
+
+    /// The fraction of the visibilities of each fine channel flagged as RFI (NaN if there were none)
+    pub fn fine_chan_occupancy(&self) -> Vec<f32> {
+        occupancy(&self.fine_chan_flagged, &self.fine_chan_total)
+    }
+
+    /// The fraction of the visibilities of each antenna flagged as RFI (NaN if there were none)
+    pub fn ant_occupancy(&self) -> Vec<f32> {
+        occupancy(&self.ant_flagged, &self.ant_total)
+    }
+}
+
+fn occupancy(flagged: &[u64], total: &[u64]) -> Vec<f32> {
+    flagged
+        .iter()
+        .zip(total)
+        .map(|(f, t)| {
+            if *t > 0 {
+                (*f as f64 / *t as f64) as f32
+            } else {
+                f32::NAN
+            }
+        })
+        .collect()
+}
+
+/// Outputs two binary files for an observation with the RFI flag `occupancy` of the coarse channels in
+/// `coarse_chan_range`: one per fine channel and one per tile.
+///
+/// The files are named OBSID_rfi_chans_chFIRST-LAST.dat and OBSID_rfi_128T_chFIRST-LAST.dat (128 is the number of
+/// tiles which may vary, FIRST and LAST are the first and last receiver channel numbers)
+///
+/// Fine channel file format 2 floats * fine channels of all coarse channels in the range:
+/// [fine chan freq][occupancy]
+///
+///     fine chan freq (MHz)
+///     occupancy (fraction of the visibilities which were flagged as RFI, 0..1)
+///
+/// Tile file format 2 floats * tiles:
+/// [ant][occupancy]
+///
+///     ant (antenna index)
+///     occupancy (fraction of the visibilities of the tile's baselines which were flagged as RFI, 0..1)
+///
+/// Visibilities which were already flagged (e.g. flagged tiles) are not counted, and if there are none left the
+/// occupancy is NaN.
+pub fn output_rfi_occupancy(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    occupancy: &FlagOccupancy,
+    coarse_chan_range: &Range<usize>,
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_rfi_occupancy()...");
+
+    let rec_chans = format!(
+        "ch{}-{}",
+        context.coarse_chans[coarse_chan_range.start].rec_chan_number,
+        context.coarse_chans[coarse_chan_range.end - 1].rec_chan_number
+    );
+
+    let fine_chan_freq_index =
+        coarse_chan_range.start * context.metafits_context.num_corr_fine_chans_per_coarse;
+    let fine_chan_records: Vec<[f32; 2]> = occupancy
+        .fine_chan_occupancy()
+        .iter()
+        .enumerate()
+        .map(|(fine_chan, o)| {
+            [
+                (context.metafits_context.metafits_fine_chan_freqs_hz
+                    [fine_chan_freq_index + fine_chan]
+                    / 1000000.0) as f32,
+                *o,
+            ]
+        })
+        .collect();
+
+    write_occupancy_file(
+        &Path::new(output_dir).join(format!(
+            "{}_rfi_chans_{}.dat",
+            context.metafits_context.obs_id, rec_chans
+        )),
+        &fine_chan_records,
+    )?;
+
+    let ant_records: Vec<[f32; 2]> = occupancy
+        .ant_occupancy()
+        .iter()
+        .enumerate()
+        .map(|(ant, o)| [ant as f32, *o])
+        .collect();
+
+    write_occupancy_file(
+        &Path::new(output_dir).join(format!(
+            "{}_rfi_{}T_{}.dat",
+            context.metafits_context.obs_id, context.metafits_context.num_ants, rec_chans
+        )),
+        &ant_records,
+    )
+}
+
+/// Writes `records` to `output_filename` as little endian floats
+fn write_occupancy_file(
+    output_filename: &PathBuf,
+    records: &[[f32; 2]],
+) -> Result<(), MwaxStatsError> {
+    let output_file = File::create(output_filename)
+        .map_err(|e| MwaxStatsError::write_file(output_filename, e))?;
+
+    let mut writer = BufWriter::new(&output_file);
+
+    for record in records {
+        trace!("{},{}", record[0], record[1]);
+
+        writer
+            .write_all(
+                &record
+                    .iter()
+                    .flat_map(|f| f.to_le_bytes())
+                    .collect::<Vec<u8>>(),
+            )
+            .map_err(|e| MwaxStatsError::write_file(output_filename, e))?;
+    }
+
+    writer
+        .flush()
+        .map_err(|e| MwaxStatsError::write_file(output_filename, e))?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use birli::{marlu::Complex, Jones};
+
+    #[test]
+    fn test_flag_rfi() {
+        let (num_timesteps, fine_chans_per_coarse, num_baselines) = (16, 32, 3);
+        let num_fine_chans = 2 * fine_chans_per_coarse;
+        let shape = (num_timesteps, num_fine_chans, num_baselines);
+
+        // Noise around an amplitude of 10 (which differs between the coarse channels), from a simple LCG
+        let mut seed: u32 = 12345;
+        let mut noise = || {
+            seed = seed.wrapping_mul(1664525).wrapping_add(1013904223);
+            (seed >> 8) as f32 / (1 << 24) as f32 - 0.5
+        };
+        let mut data = CorrectedData {
+            jones: Array3::<Jones<f32>>::zeros(shape),
+            weights: Array3::<f32>::ones(shape),
+        };
+        for ((_, fine_chan, _), j) in data.jones.indexed_iter_mut() {
+            let level = if fine_chan < fine_chans_per_coarse {
+                10.0
+            } else {
+                12.0
+            };
+            *j = Jones::from([
+                Complex::new(level + noise(), noise()),
+                Complex::new(0.0, 0.0),
+                Complex::new(0.0, 0.0),
+                Complex::new(level + noise(), noise()),
+            ]);
+        }
+
+        // Strong narrowband RFI in fine channel 5 of baseline 1 (XX only)
+        for timestep in 0..num_timesteps {
+            data.jones[[timestep, 5, 1]][0] += Complex::new(20.0, 0.0);
+        }
+        // A weak broadband burst in timestep 7 of baseline 2 (YY only), too weak to flag in any single fine channel
+        for fine_chan in 40..48 {
+            data.jones[[7, fine_chan, 2]][3] += Complex::new(1.5, 0.0);
+        }
+        // Already flagged
+        data.weights[[0, 0, 0]] = 0.0;
+
+        let rfi_flags = flag_rfi(&mut data, fine_chans_per_coarse);
+
+        assert!((0..num_timesteps).all(|t| rfi_flags[[t, 5, 1]]));
+        assert!((40..48).all(|c| rfi_flags[[7, c, 2]]));
+        assert!(!rfi_flags[[0, 0, 0]]);
+        assert!(rfi_flags
+            .indexed_iter()
+            .all(|((t, c, bl), f)| !*f || data.weights[[t, c, bl]] == 0.0));
+
+        // Very little of the clean data is flagged
+        let num_clean_flagged = rfi_flags
+            .indexed_iter()
+            .filter(|((t, c, bl), f)| **f && !(*c == 5 && *bl == 1) && !(*t == 7 && *bl == 2))
+            .count();
+        assert!(num_clean_flagged < data.jones.len() / 100);
+
+        // Occupancy
+        let baselines = [(0, 0), (0, 1), (1, 1)].map(|(ant1_index, ant2_index)| Baseline {
+            ant1_index,
+            ant2_index,
+        });
+        let mut occupancy = FlagOccupancy::new(num_fine_chans, 2);
+        occupancy.add(&data, &rfi_flags, &baselines);
+
+        let fine_chan_occupancy = occupancy.fine_chan_occupancy();
+        assert!(fine_chan_occupancy[5] >= 1.0 / 3.0);
+        assert!(fine_chan_occupancy[20] < 0.1);
+        assert_eq!(occupancy.fine_chan_total[0], 3 * num_timesteps as u64 - 1);
+
+        let ant_occupancy = occupancy.ant_occupancy();
+        assert!(ant_occupancy[1] > ant_occupancy[0]);
+    }
+
+    #[test]
+    fn test_sum_threshold_1d() {
+        let residuals = ndarray::array![0.0f32, 1.0, 0.6, 0.6, 0.0, 2.0];
+        let flags = ndarray::array![false, false, false, false, false, true];
+
+        // Single samples above the threshold
+        let mut new_flags = flags.clone();
+        sum_threshold_1d(residuals.view(), flags.view(), 1, 0.8, new_flags.view_mut());
+        assert_eq!(
+            new_flags,
+            ndarray::array![false, true, false, false, false, true]
+        );
+
+        // Pairs whose mean is above the threshold. The flagged sample counts as the threshold
+        let mut new_flags = flags.clone();
+        sum_threshold_1d(
+            residuals.view(),
+            flags.view(),
+            2,
+            0.55,
+            new_flags.view_mut(),
+        );
+        assert_eq!(
+            new_flags,
+            ndarray::array![false, true, true, true, false, true]
+        );
+    }
+}
@@ -0,0 +1,377 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::errors::MwaxStatsError;
+use crate::processing::Corrections;
+use birli::{io::aocal::AOCalSols, marlu::Complex, Jones};
+use clap::crate_version;
+use core::ops::Range;
+use log::info;
+use mwalib::{
+    fitsio::{
+        errors::Error as FitsError,
+        hdu::HduInfo,
+        images::{ImageDescription, ImageType},
+        FitsFile,
+    },
+    CorrelatorContext,
+};
+use ndarray::{Array2, Array3, Array4, Axis};
+use std::path::Path;
+
+/// Writes the per-tile `gains` ([ant][fine chan][pol], from `gains::solve_gains`) of the coarse channels in
+/// `coarse_chan_range` as a calibration solutions FITS file in the same layout as mwa_hyperdrive.
+///
+/// The file is named OBSID_solutions_chFIRST-LAST.fits (FIRST and LAST are the first and last receiver channel
+/// numbers)
+///
+/// The fine channel axis covers every coarse channel of the observation so the file can be applied to any subset of
+/// the coarse channels; channels without a solution (and flagged tiles) are NaN. XY and YX are always zero.
+/// See `write_calibration_solutions` for the layout.
+pub fn output_calibration_solutions(
+    context: &CorrelatorContext,
+    output_dir: &str,
+    gains: &Array3<Complex<f64>>,
+    coarse_chan_range: &Range<usize>,
+    corrections: &Corrections,
+) -> Result<(), MwaxStatsError> {
+    info!("Starting output_calibration_solutions()...");
+
+    let fine_chans_per_coarse = context.metafits_context.num_corr_fine_chans_per_coarse;
+    let total_num_fine_chans = context.num_coarse_chans * fine_chans_per_coarse;
+    let (num_ants, num_fine_chans, _) = gains.dim();
+
+    // The gains array starts at the first coarse channel of the range
+    let fine_chan_offset = coarse_chan_range.start * fine_chans_per_coarse;
+
+    let nan = Complex::new(f64::NAN, f64::NAN);
+    let zero = Complex::new(0.0, 0.0);
+    let mut solutions =
+        Array2::<Jones<f64>>::from_elem((num_ants, total_num_fine_chans), Jones::from([nan; 4]));
+
+    for ant in 0..num_ants {
+        for fine_chan in 0..num_fine_chans {
+            let xx = gains[[ant, fine_chan, 0]];
+            let yy = gains[[ant, fine_chan, 1]];
+
+            if !xx.is_nan() && !yy.is_nan() {
+                solutions[[ant, fine_chan_offset + fine_chan]] = Jones::from([xx, zero, zero, yy]);
+            }
+        }
+    }
+
+    let output_filename = Path::new(output_dir).join(format!(
+        "{}_solutions_ch{}-{}.fits",
+        context.metafits_context.obs_id,
+        context.coarse_chans[coarse_chan_range.start].rec_chan_number,
+        context.coarse_chans[coarse_chan_range.end - 1].rec_chan_number
+    ));
+
+    write_calibration_solutions(
+        &output_filename,
+        context.metafits_context.obs_id,
+        &solutions,
+        corrections,
+    )?;
+
+    info!("Done! {} written.", output_filename.display());
+
+    Ok(())
+}
+
+/// Writes `solutions` ([tile][fine chan]) as a calibration solutions FITS file in the same layout as mwa_hyperdrive.
+///
+/// The "SOLUTIONS" HDU is a 4D f64 image of [timeblock][tile][fine chan][8] where the last axis is the real and
+/// imaginary parts of the XX, XY, YX and YY Jones matrix elements. There is a single timeblock.
+///
+/// The Jones matrices follow the hyperdrive convention: data = J1 * model * J2^H.
+///
+/// The `corrections` applied to the data the gains were solved from are recorded in the primary HDU (1 = applied).
+fn write_calibration_solutions(
+    output_filename: &Path,
+    obs_id: u32,
+    solutions: &Array2<Jones<f64>>,
+    corrections: &Corrections,
+) -> Result<(), MwaxStatsError> {
+    let (num_tiles, num_fine_chans) = solutions.dim();
+
+    let mut image = Array4::<f64>::zeros((1, num_tiles, num_fine_chans, 8));
+    for ((tile, fine_chan), jones) in solutions.indexed_iter() {
+        for (pol, c) in jones.iter().enumerate() {
+            image[[0, tile, fine_chan, pol * 2]] = c.re;
+            image[[0, tile, fine_chan, pol * 2 + 1]] = c.im;
+        }
+    }
+
+    // The image was created in standard layout so the raw data is in the right order
+    let (image_data, _) = image.into_raw_vec_and_offset();
+
+    let write = || -> Result<(), FitsError> {
+        let mut fits_file = FitsFile::create(output_filename).overwrite().open()?;
+
+        let primary_hdu = fits_file.primary_hdu()?;
+        primary_hdu.write_key(&mut fits_file, "OBSID", obs_id as i64)?;
+        primary_hdu.write_key(
+            &mut fits_file,
+            "SOFTWARE",
+            format!("mwax_stats {}", crate_version!()),
+        )?;
+        for (key, value, comment) in [
+            ("VANVLECK", corrections.van_vleck, "Van Vleck corrected"),
+            (
+                "CABLELEN",
+                corrections.cable_lengths,
+                "Cable lengths corrected",
+            ),
+            (
+                "DIGGAINS",
+                corrections.digital_gains,
+                "Digital gains corrected",
+            ),
+            (
+                "PFBGAINS",
+                corrections.passband_gains,
+                "Passband gains corrected",
+            ),
+            (
+                "GEOMETRY",
+                corrections.geometry,
+                "Geometric delays corrected",
+            ),
+        ] {
+            primary_hdu.write_key(&mut fits_file, key, (value as i64, comment))?;
+        }
+
+        let image_description = ImageDescription {
+            data_type: ImageType::Double,
+            dimensions: &[1, num_tiles, num_fine_chans, 8],
+        };
+        let solutions_hdu = fits_file.create_image("SOLUTIONS", &image_description)?;
+        solutions_hdu.write_image(&mut fits_file, &image_data)?;
+
+        Ok(())
+    };
+
+    write().map_err(|e| MwaxStatsError::WriteFits {
+        filename: output_filename.to_path_buf(),
+        source: e,
+    })
+}
+
+/// Reads the first timeblock of a hyperdrive format calibration solutions FITS file.
+///
+/// Returns the solutions as [tile][fine chan] Jones matrices in the hyperdrive convention: data = J1 * model * J2^H.
+fn read_calibration_solutions(filename: &Path) -> Result<Array2<Jones<f64>>, MwaxStatsError> {
+    let invalid = |reason: String| MwaxStatsError::ReadCalibrationSolutions {
+        filename: filename.to_path_buf(),
+        reason,
+    };
+
+    let mut fits_file = FitsFile::open(filename).map_err(|e| invalid(e.to_string()))?;
+
+    let solutions_hdu = fits_file
+        .hdu("SOLUTIONS")
+        .map_err(|e| invalid(format!("Unable to find SOLUTIONS HDU: {}", e)))?;
+
+    let shape = match &solutions_hdu.info {
+        HduInfo::ImageInfo { shape, .. } if shape.len() == 4 && shape[3] == 8 && shape[0] > 0 => {
+            shape.clone()
+        }
+        _ => {
+            return Err(invalid(
+                "SOLUTIONS HDU is not a [timeblock][tile][fine chan][8] image".to_string(),
+            ))
+        }
+    };
+
+    let image: Vec<f64> = solutions_hdu
+        .read_image(&mut fits_file)
+        .map_err(|e| invalid(e.to_string()))?;
+
+    let image = Array4::from_shape_vec((shape[0], shape[1], shape[2], shape[3]), image)
+        .map_err(|_| invalid("SOLUTIONS HDU is not the expected size".to_string()))?;
+
+    Ok(image.index_axis(Axis(0), 0).map_axis(Axis(2), |v| {
+        Jones::from([
+            Complex::new(v[0], v[1]),
+            Complex::new(v[2], v[3]),
+            Complex::new(v[4], v[5]),
+            Complex::new(v[6], v[7]),
+        ])
+    }))
+}
+
+/// Reads a calibration solutions file so that it can be applied by birli. Files ending in .bin are read as André
+/// Offringa's calibrate format, anything else as a hyperdrive format FITS file (e.g. from `output_calibration_solutions`).
+///
+/// Only the first timeblock is used. Returns [tile][fine chan] Jones matrices, covering all coarse channels of the
+/// observation, which birli will multiply the data by (J1 * data * J2^H).
+///
+/// The solutions must be for an observation with `num_ants` tiles and `num_coarse_chans` coarse channels of
+/// `fine_chans_per_coarse` fine channels (see `check_calsols_shape`), otherwise birli would either panic, fail when
+/// preprocessing or silently not apply them.
+pub fn read_calsols(
+    filename: &Path,
+    num_ants: usize,
+    num_coarse_chans: usize,
+    fine_chans_per_coarse: usize,
+) -> Result<Array2<Jones<f64>>, MwaxStatsError> {
+    info!("Reading calibration solutions from {}", filename.display());
+
+    let calsols = match filename.extension().and_then(|e| e.to_str()) {
+        // This is the same as birli itself does
+        Some("bin") => AOCalSols::read_andre_binary(filename)
+            .map(|calsols| calsols.di_jones.index_axis(Axis(0), 0).to_owned())
+            .map_err(|e| MwaxStatsError::ReadCalibrationSolutions {
+                filename: filename.to_path_buf(),
+                reason: e.to_string(),
+            })?,
+        // hyperdrive solutions are the gains, so need to be inverted to correct the data
+        _ => read_calibration_solutions(filename)?.mapv(|j| j.inv()),
+    };
+
+    check_calsols_shape(&calsols, num_ants, num_coarse_chans, fine_chans_per_coarse).map_err(
+        |reason| MwaxStatsError::ReadCalibrationSolutions {
+            filename: filename.to_path_buf(),
+            reason,
+        },
+    )?;
+
+    Ok(calsols)
+}
+
+/// Checks that `calsols` ([tile][fine chan]) has a solution for each of `num_ants` tiles, and the same (non-zero)
+/// number of fine channels for each of `num_coarse_chans` coarse channels, so they can be sliced per coarse channel.
+/// Birli also needs the `fine_chans_per_coarse` of the data to be a whole multiple of the solutions' fine channels
+/// per coarse channel. Returns the reason if not.
+fn check_calsols_shape(
+    calsols: &Array2<Jones<f64>>,
+    num_ants: usize,
+    num_coarse_chans: usize,
+    fine_chans_per_coarse: usize,
+) -> Result<(), String> {
+    let (num_tiles, num_fine_chans) = calsols.dim();
+
+    if num_tiles != num_ants {
+        return Err(format!(
+            "The solutions are for {} tiles but the observation has {}",
+            num_tiles, num_ants
+        ));
+    }
+    if num_fine_chans == 0 || num_fine_chans % num_coarse_chans != 0 {
+        return Err(format!(
+            "The solutions have {} fine channels, which is not a multiple of the {} coarse channels of the observation",
+            num_fine_chans, num_coarse_chans
+        ));
+    }
+    let calsol_fine_chans_per_coarse = num_fine_chans / num_coarse_chans;
+    if !fine_chans_per_coarse.is_multiple_of(calsol_fine_chans_per_coarse) {
+        return Err(format!(
+            "The solutions have {} fine channels per coarse channel, which does not divide the {} of the observation",
+            calsol_fine_chans_per_coarse, fine_chans_per_coarse
+        ));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_write_read_calibration_solutions() {
+        let dir = tempfile::tempdir().unwrap();
+        let filename = &dir.path().join("1234567890_solutions.fits");
+
+        let mut solutions = Array2::<Jones<f64>>::from_elem((3, 4), Jones::identity());
+        solutions[[1, 2]] = Jones::from([
+            Complex::new(1.0, 2.0),
+            Complex::new(0.0, 0.0),
+            Complex::new(0.0, 0.0),
+            Complex::new(3.0, -4.0),
+        ]);
+        solutions[[2, 3]] = Jones::from([Complex::new(f64::NAN, f64::NAN); 4]);
+
+        let corrections = Corrections {
+            cable_lengths: true,
+            ..Default::default()
+        };
+        write_calibration_solutions(filename, 1234567890, &solutions, &corrections).unwrap();
+
+        let mut fits_file = FitsFile::open(filename).unwrap();
+        let primary_hdu = fits_file.primary_hdu().unwrap();
+        assert_eq!(
+            primary_hdu
+                .read_key::<i64>(&mut fits_file, "CABLELEN")
+                .unwrap(),
+            1
+        );
+        assert_eq!(
+            primary_hdu
+                .read_key::<i64>(&mut fits_file, "GEOMETRY")
+                .unwrap(),
+            0
+        );
+
+        let read = read_calibration_solutions(filename).unwrap();
+
+        assert_eq!(read.dim(), (3, 4));
+        assert_eq!(read[[0, 0]], Jones::identity());
+        assert_eq!(read[[1, 2]], solutions[[1, 2]]);
+        assert!(read[[2, 3]].any_nan());
+
+        // Not a solutions file
+        assert!(matches!(
+            read_calsols(&dir.path().join("does_not_exist_solutions.fits"), 3, 2, 2),
+            Err(MwaxStatsError::ReadCalibrationSolutions { .. })
+        ));
+
+        // Applying to birli inverts the gains
+        let calsols = read_calsols(filename, 3, 2, 2).unwrap();
+        assert_eq!(calsols[[1, 2]], solutions[[1, 2]].inv());
+
+        // The solutions must match the observation
+        assert!(matches!(
+            read_calsols(filename, 4, 2, 2),
+            Err(MwaxStatsError::ReadCalibrationSolutions { .. })
+        ));
+        assert!(matches!(
+            read_calsols(filename, 3, 8, 2),
+            Err(MwaxStatsError::ReadCalibrationSolutions { .. })
+        ));
+        assert!(matches!(
+            read_calsols(filename, 3, 2, 3),
+            Err(MwaxStatsError::ReadCalibrationSolutions { .. })
+        ));
+    }
+
+    #[test]
+    fn test_check_calsols_shape() {
+        let calsols = Array2::<Jones<f64>>::from_elem((3, 8), Jones::identity());
+
+        assert!(check_calsols_shape(&calsols, 3, 1, 8).is_ok());
+        assert!(check_calsols_shape(&calsols, 3, 4, 2).is_ok());
+        // A lower resolution than the data is applied to each of its fine channels
+        assert!(check_calsols_shape(&calsols, 3, 4, 128).is_ok());
+
+        // Fewer tiles than the observation (birli would panic)
+        assert!(check_calsols_shape(&calsols, 4, 4, 128).is_err());
+        // More tiles than the observation
+        assert!(check_calsols_shape(&calsols, 2, 4, 128).is_err());
+
+        // Fewer fine channels than coarse channels (none would be applied)
+        assert!(check_calsols_shape(&calsols, 3, 16, 128).is_err());
+        // Not the same number of fine channels for each coarse channel
+        assert!(check_calsols_shape(&calsols, 3, 3, 128).is_err());
+        // No fine channels at all
+        let empty = Array2::<Jones<f64>>::from_elem((3, 0), Jones::identity());
+        assert!(check_calsols_shape(&empty, 3, 4, 128).is_err());
+
+        // Resolutions which do not divide that of the data (birli would fail with a channel size mismatch)
+        let calsols_48 = Array2::<Jones<f64>>::from_elem((3, 4 * 48), Jones::identity());
+        assert!(check_calsols_shape(&calsols_48, 3, 4, 128).is_err());
+        let calsols_256 = Array2::<Jones<f64>>::from_elem((3, 4 * 256), Jones::identity());
+        assert!(check_calsols_shape(&calsols_256, 3, 4, 128).is_err());
+    }
+}
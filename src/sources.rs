@@ -0,0 +1,81 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use birli::marlu::RADec;
+
+/// Bright sources commonly used to calibrate the MWA: name, J2000 RA (deg) and Dec (deg)
+pub const CALIBRATORS: [(&str, f64, f64); 11] = [
+    ("3C444", 333.6073, -17.0267),
+    ("3C161", 96.7921, -5.8847),
+    ("CasA", 350.8500, 58.8150),
+    ("CenA", 201.3651, -43.0191),
+    ("CygA", 299.8682, 40.7339),
+    ("ForA", 50.6738, -37.2083),
+    ("HerA", 252.7838, 4.9926),
+    ("HydA", 139.5237, -12.0956),
+    ("PicA", 79.9572, -45.7789),
+    ("TauA", 83.6331, 22.0145),
+    ("VirA", 187.7059, 12.3911),
+];
+
+/// Looks up a source in `CALIBRATORS` by name (ignoring case)
+pub fn find_calibrator(name: &str) -> Option<RADec> {
+    CALIBRATORS
+        .iter()
+        .find(|(calibrator, _, _)| calibrator.eq_ignore_ascii_case(name.trim()))
+        .map(|(_, ra, dec)| RADec::from_degrees(*ra, *dec))
+}
+
+/// Parses a phase centre given on the command line as either "RA,DEC" in degrees e.g. "79.96,-45.78" or the name of
+/// one of the `CALIBRATORS` e.g. "PicA".
+pub fn parse_phase_centre(phase_centre: &str) -> Result<RADec, String> {
+    if let Some(radec) = find_calibrator(phase_centre) {
+        return Ok(radec);
+    }
+
+    let invalid = || {
+        format!(
+            "Invalid phase centre '{}': expected RA,DEC in degrees or one of {}",
+            phase_centre,
+            CALIBRATORS
+                .iter()
+                .map(|(name, _, _)| *name)
+                .collect::<Vec<&str>>()
+                .join(", ")
+        )
+    };
+
+    let radec: Vec<f64> = phase_centre
+        .split(',')
+        .map(|d| d.trim().parse::<f64>())
+        .collect::<Result<_, _>>()
+        .map_err(|_| invalid())?;
+
+    match radec[..] {
+        [ra, dec] if (0.0..360.0).contains(&ra) && (-90.0..=90.0).contains(&dec) => {
+            Ok(RADec::from_degrees(ra, dec))
+        }
+        _ => Err(invalid()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_phase_centre() {
+        let pica = parse_phase_centre("pica").unwrap();
+        assert!((pica.ra.to_degrees() - 79.9572).abs() < 1e-9);
+        assert!((pica.dec.to_degrees() + 45.7789).abs() < 1e-9);
+
+        let radec = parse_phase_centre("10.5, -26.7").unwrap();
+        assert!((radec.ra.to_degrees() - 10.5).abs() < 1e-9);
+        assert!((radec.dec.to_degrees() + 26.7).abs() < 1e-9);
+
+        assert!(parse_phase_centre("NotASource").is_err());
+        assert!(parse_phase_centre("10.5").is_err());
+        assert!(parse_phase_centre("360.0,0.0").is_err());
+        assert!(parse_phase_centre("0.0,91.0").is_err());
+    }
+}
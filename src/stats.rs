@@ -0,0 +1,525 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at http://mozilla.org/MPL/2.0/.
+use crate::closures::{self, BispectrumSum};
+use crate::errors::MwaxStatsError;
+use crate::fringes::FringeRecordVersion;
+use crate::header::{ObsInfo, OutputFormat};
+use crate::numpy::{FileFormat, NpyWriter, NpzWriter};
+use crate::processing::{
+    self, CorrectionMode, Corrections, SelectionPolicy, TimestepSelection, VisSum,
+};
+use crate::rfi::{self, FlagOccupancy};
+use crate::{autos, delays, fringes, gains, solutions};
+use birli::marlu::{Jones, RADec};
+use log::{debug, info, warn};
+use mwalib::{CorrelatorContext, MetafitsContext};
+use ndarray::Array2;
+use std::ops::Range;
+use std::path::Path;
+
+/// Everything `run` needs to know. The `mwax_stats` executable fills this in from its command line; `new` gives the
+/// same defaults.
+#[derive(Clone, Debug)]
+pub struct StatsOptions {
+    pub metafits_filename: String,
+    pub fits_files: Vec<String>,
+    pub output_dir: String,
+
+    /// Which timesteps and coarse channels to use, unless `timestep_indices`, `gps_times` or `coarse_chan_indices`
+    /// are given
+    pub selection_policy: SelectionPolicy,
+    /// Fall back to the common timesteps if there are no common good timesteps
+    pub use_any_timestep: bool,
+    pub timestep_selection: TimestepSelection,
+    pub timestep_indices: Option<Vec<usize>>,
+    /// GPS times (s) of the timesteps to use, instead of `timestep_indices`
+    pub gps_times: Option<Vec<f64>>,
+    pub coarse_chan_indices: Option<Vec<usize>>,
+    pub max_memory_gb: Option<f32>,
+    /// Process all timesteps in chunks which fit within `max_memory_gb`, rather than just the first chunk
+    pub chunked: bool,
+
+    pub average_autos: bool,
+    pub autos_stats: bool,
+    pub autos_waterfall: bool,
+    pub autos_cross_pols: bool,
+    /// Only for `FileFormat::Dat`
+    pub output_format: OutputFormat,
+    pub file_format: FileFormat,
+
+    /// Produce fringes etc. even if this is not a calibrator observation
+    pub force_fringes: bool,
+    pub fringes_version: FringeRecordVersion,
+    pub phase_centre: Option<RADec>,
+    pub van_vleck: bool,
+    pub cable_lengths: CorrectionMode,
+    pub digital_gains: CorrectionMode,
+    pub passband_gains: CorrectionMode,
+    pub geometry: CorrectionMode,
+    pub calsols_filename: Option<String>,
+    pub rfi_flagging: bool,
+    pub delays: bool,
+    pub gains: bool,
+    /// Also write the gains as calibration solutions (needs `gains`)
+    pub solutions: bool,
+    pub closure_triads: Vec<[usize; 3]>,
+}
+
+impl StatsOptions {
+    pub fn new(metafits_filename: &str, fits_files: &[&str], output_dir: &str) -> Self {
+        StatsOptions {
+            metafits_filename: metafits_filename.to_string(),
+            fits_files: fits_files.iter().map(|f| f.to_string()).collect(),
+            output_dir: output_dir.to_string(),
+            selection_policy: SelectionPolicy::CommonGood,
+            use_any_timestep: false,
+            timestep_selection: TimestepSelection::All,
+            timestep_indices: None,
+            gps_times: None,
+            coarse_chan_indices: None,
+            max_memory_gb: None,
+            chunked: false,
+            average_autos: false,
+            autos_stats: false,
+            autos_waterfall: false,
+            autos_cross_pols: false,
+            output_format: OutputFormat::Legacy,
+            file_format: FileFormat::Dat,
+            force_fringes: false,
+            fringes_version: FringeRecordVersion::V1,
+            phase_centre: None,
+            van_vleck: false,
+            cable_lengths: CorrectionMode::Auto,
+            digital_gains: CorrectionMode::Auto,
+            passband_gains: CorrectionMode::Auto,
+            geometry: CorrectionMode::Auto,
+            calsols_filename: None,
+            rfi_flagging: false,
+            delays: false,
+            gains: false,
+            solutions: false,
+            closure_triads: vec![],
+        }
+    }
+}
+
+/// The sums over timesteps of the corrected data which the fringes, delays, gains, closure phases and RFI occupancy
+/// are made from
+pub struct CalibratorSums {
+    /// The timesteps which contributed to the sums: those read (fewer than asked for if they did not fit in memory and
+    /// we were not chunking) which had any unflagged data
+    pub timestep_indices: Vec<usize>,
+    pub vis_sum: VisSum,
+    /// [triad][XX, YY], in the order of `StatsOptions::closure_triads`
+    pub bispectra: Vec<[BispectrumSum; 2]>,
+    /// Only if `StatsOptions::rfi_flagging` is set
+    pub rfi_occupancy: Option<FlagOccupancy>,
+}
+
+/// Opens the metafits and gpubox files, telling apart a bad metafits from bad gpubox files
+pub fn open_context(
+    metafits_filename: &str,
+    fits_files: &[String],
+) -> Result<CorrelatorContext, MwaxStatsError> {
+    CorrelatorContext::new(metafits_filename, fits_files).map_err(|e| {
+        // Work out whether it was the metafits or the gpubox files which were the problem
+        match MetafitsContext::new(metafits_filename, None) {
+            Err(metafits_error) => MwaxStatsError::OpenMetafits(metafits_error),
+            Ok(_) => MwaxStatsError::OpenGpuboxFiles(e),
+        }
+    })
+}
+
+/// The timestep and coarse channel ranges chosen by the selection settings of `options`. Every index in the ranges is
+/// used, so explicit timesteps (including those of `gps_times`) or coarse channels which are not contiguous are an
+/// `InvalidArgument` error rather than being spanned.
+pub fn select_ranges(
+    context: &CorrelatorContext,
+    options: &StatsOptions,
+) -> Result<(Range<usize>, Range<usize>), MwaxStatsError> {
+    // GPS times are the same as giving the timestep indices
+    let timestep_indices = match &options.gps_times {
+        Some(gps_times) => {
+            let timestep_indices =
+                processing::get_timestep_indices_from_gps_times(context, gps_times)?;
+            info!(
+                "GPS times {:?} are in timesteps {:?}.",
+                gps_times, timestep_indices
+            );
+            Some(timestep_indices)
+        }
+        None => options.timestep_indices.clone(),
+    };
+
+    // Explicit timesteps and/or coarse channels override the selection policy
+    let selection_policy = if timestep_indices.is_some() || options.coarse_chan_indices.is_some() {
+        SelectionPolicy::Explicit {
+            timestep_indices,
+            coarse_chan_indices: options.coarse_chan_indices.clone(),
+        }
+    } else {
+        options.selection_policy.clone()
+    };
+
+    let selection = processing::get_timesteps_coarse_chan_ranges(
+        context,
+        &selection_policy,
+        options.timestep_selection,
+    );
+
+    match selection {
+        Err(MwaxStatsError::NoCommonGoodTimestepCCFound) if options.use_any_timestep => {
+            info!("No common good timesteps found, using the common timesteps instead.");
+            processing::get_timesteps_coarse_chan_ranges(
+                context,
+                &SelectionPolicy::Common,
+                options.timestep_selection,
+            )
+        }
+        result => result,
+    }
+}
+
+/// The corrections from the metafits, unless overridden in `options`
+pub fn get_corrections(context: &CorrelatorContext, options: &StatsOptions) -> Corrections {
+    let auto_corrections = Corrections::auto(&context.metafits_context, options.van_vleck);
+    Corrections {
+        van_vleck: options.van_vleck,
+        cable_lengths: options
+            .cable_lengths
+            .resolve(auto_corrections.cable_lengths),
+        digital_gains: options
+            .digital_gains
+            .resolve(auto_corrections.digital_gains),
+        passband_gains: options
+            .passband_gains
+            .resolve(auto_corrections.passband_gains),
+        geometry: options.geometry.resolve(auto_corrections.geometry),
+    }
+}
+
+/// Reads and corrects the data one chunk of timesteps at a time (see `StatsOptions::max_memory_gb` and `chunked`),
+/// summing it over time. Nothing is written.
+pub fn get_calibrator_sums(
+    context: &CorrelatorContext,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+    corrections: &Corrections,
+    calsols: Option<&Array2<Jones<f64>>>,
+    options: &StatsOptions,
+) -> Result<CalibratorSums, MwaxStatsError> {
+    closures::check_triads(&options.closure_triads, context.metafits_context.num_ants)?;
+
+    // Work out how many timesteps we can correct at once
+    let memory_model =
+        processing::MemoryModel::for_corrected_data(context, coarse_chan_range.len());
+    let mut timestep_chunks =
+        processing::get_timestep_chunks(timestep_range, &memory_model, options.max_memory_gb)?;
+
+    if timestep_chunks.len() > 1 {
+        if options.chunked {
+            info!(
+                "Processing {} timesteps in {} chunks of up to {} timesteps.",
+                timestep_range.len(),
+                timestep_chunks.len(),
+                timestep_chunks[0].len()
+            );
+        } else {
+            warn!(
+                "Only the first {} of {} timesteps fit within the memory limit, the rest will not be used (use --chunked to use them all).",
+                timestep_chunks[0].len(),
+                timestep_range.len()
+            );
+            timestep_chunks.truncate(1);
+        }
+    }
+
+    let num_fine_chans =
+        coarse_chan_range.len() * context.metafits_context.num_corr_fine_chans_per_coarse;
+    let mut vis_sum = VisSum::new(num_fine_chans, context.metafits_context.num_baselines);
+    let mut bispectra = vec![[BispectrumSum::default(); 2]; options.closure_triads.len()];
+    let mut rfi_occupancy = options
+        .rfi_flagging
+        .then(|| FlagOccupancy::new(num_fine_chans, context.metafits_context.num_ants));
+    let mut timestep_indices = Vec::new();
+
+    // Get data one chunk at a time. This is shared by all of the products
+    for timestep_chunk in &timestep_chunks {
+        let mut corrected_data = processing::get_corrected_data(
+            context,
+            timestep_chunk,
+            coarse_chan_range,
+            corrections,
+            calsols,
+            options.phase_centre.as_ref(),
+        )?;
+
+        if let Some(rfi_occupancy) = rfi_occupancy.as_mut() {
+            let rfi_flags = rfi::flag_rfi(
+                &mut corrected_data,
+                context.metafits_context.num_corr_fine_chans_per_coarse,
+            );
+            rfi_occupancy.add(
+                &corrected_data,
+                &rfi_flags,
+                &context.metafits_context.baselines,
+            );
+        }
+
+        vis_sum.add(&corrected_data);
+        timestep_indices.extend(
+            corrected_data
+                .unflagged_timesteps()
+                .into_iter()
+                .map(|timestep| timestep_chunk.start + timestep),
+        );
+
+        closures::accumulate_bispectra(
+            &corrected_data,
+            context.metafits_context.num_ants,
+            &options.closure_triads,
+            &mut bispectra,
+        );
+    }
+
+    Ok(CalibratorSums {
+        timestep_indices,
+        vis_sum,
+        bispectra,
+        rfi_occupancy,
+    })
+}
+
+/// Produces all of the outputs asked for in `options`: the autos always, and the fringes etc. for calibrator
+/// observations (or if `force_fringes` is set).
+///
+/// # Returns
+///
+/// * Result containing nothing if all outputs were written, or the MwaxStatsError which stopped us
+///
+pub fn run(options: &StatsOptions) -> Result<(), MwaxStatsError> {
+    // Create correlator context
+    let context = open_context(&options.metafits_filename, &options.fits_files)?;
+
+    // Always print the obs info
+    processing::print_info(&context);
+
+    // Determine timestep and coarse channel range. This is shared by the autos and fringes etc
+    // By default we only want all the common good timesteps if possible; and all of the coarse channels
+    let (timestep_range, coarse_chan_range) = select_ranges(&context, options)?;
+
+    // Output the timestep and coarse channel ranges and debug
+    debug!(
+        "Timesteps   : {} indicies: {}..{}",
+        timestep_range.len(),
+        timestep_range.start,
+        timestep_range.end - 1
+    );
+    debug!(
+        "Coarse chans: {} indicies: {}..{}",
+        coarse_chan_range.len(),
+        coarse_chan_range.start,
+        coarse_chan_range.end - 1
+    );
+
+    // Always produce autocorrelations (one file per coarse channel, unless writing a .npz bundle)
+    let mut writers = Writers::new(&context, options);
+    autos::output_autocorrelations(
+        &context,
+        writers.autos(),
+        &timestep_range,
+        &coarse_chan_range,
+        options.average_autos,
+        options.autos_stats,
+        options.autos_waterfall,
+        options.autos_cross_pols,
+        options.van_vleck,
+    )?;
+
+    // Only produce fringes for calibrator observations (unless forced)
+    if context.metafits_context.calibrator || options.force_fringes {
+        if !context.metafits_context.calibrator {
+            info!("Producing fringes etc. for a non-calibrator observation as --force-fringes was given.");
+        }
+
+        output_calibrator_products(
+            &context,
+            options,
+            &mut writers,
+            &timestep_range,
+            &coarse_chan_range,
+        )?;
+    } else {
+        info!("Skipping output_fringes(), output_delays(), output_closure_phases() and output_gains() as this is not a calibrator observation (use --force-fringes to produce them anyway).");
+    }
+
+    writers.finish()
+}
+
+/// Produces the fringes, delays, closure phases, gains and RFI occupancy asked for in `options`
+fn output_calibrator_products(
+    context: &CorrelatorContext,
+    options: &StatsOptions,
+    writers: &mut Writers,
+    timestep_range: &Range<usize>,
+    coarse_chan_range: &Range<usize>,
+) -> Result<(), MwaxStatsError> {
+    let output_dir = &options.output_dir;
+
+    // Work out the corrections from the metafits, unless overridden
+    let corrections = get_corrections(context, options);
+    info!("Correcting for Van Vleck     : {}.", corrections.van_vleck);
+    info!(
+        "Correcting for cable lengths : {} ({:?}).",
+        corrections.cable_lengths, options.cable_lengths
+    );
+    info!(
+        "Correcting for digital gains : {} ({:?}).",
+        corrections.digital_gains, options.digital_gains
+    );
+    info!(
+        "Correcting for passband gains: {} ({:?}).",
+        corrections.passband_gains, options.passband_gains
+    );
+    info!(
+        "Correcting for geometry      : {} ({:?}).",
+        corrections.geometry, options.geometry
+    );
+    processing::output_corrections(context, output_dir, &corrections, coarse_chan_range)?;
+
+    if let Some(phase_centre) = &options.phase_centre {
+        info!("Phase centre                 : {}.", phase_centre);
+        if !corrections.geometry {
+            warn!("The phase centre is ignored as the geometric correction is off (use --geometry on).");
+        }
+    }
+
+    // Read calibration solutions if we were given any
+    let calsols = options
+        .calsols_filename
+        .as_ref()
+        .map(|f| {
+            solutions::read_calsols(
+                Path::new(f),
+                context.metafits_context.num_ants,
+                context.num_coarse_chans,
+                context.metafits_context.num_corr_fine_chans_per_coarse,
+            )
+        })
+        .transpose()?;
+
+    let sums = get_calibrator_sums(
+        context,
+        timestep_range,
+        coarse_chan_range,
+        &corrections,
+        calsols.as_ref(),
+        options,
+    )?;
+
+    if let Some(rfi_occupancy) = &sums.rfi_occupancy {
+        rfi::output_rfi_occupancy(context, output_dir, rfi_occupancy, coarse_chan_range)?;
+    }
+
+    fringes::output_fringes(
+        context,
+        writers.fringes(),
+        &sums.vis_sum,
+        &sums.timestep_indices,
+        coarse_chan_range,
+        &corrections,
+    )?;
+
+    // Products of the visibilities vector averaged over all timesteps
+    let averaged = sums.vis_sum.average();
+
+    if options.delays {
+        delays::output_delays(context, output_dir, &averaged, coarse_chan_range)?;
+    }
+
+    if !options.closure_triads.is_empty() {
+        closures::output_closure_phases(
+            context,
+            output_dir,
+            &sums.bispectra,
+            coarse_chan_range,
+            &options.closure_triads,
+        )?;
+    }
+
+    if options.gains {
+        let gains = gains::solve_gains(context, &averaged);
+        gains::output_gains(context, output_dir, &gains, coarse_chan_range)?;
+
+        if options.solutions {
+            solutions::output_calibration_solutions(
+                context,
+                output_dir,
+                &gains,
+                coarse_chan_range,
+                &corrections,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The writers of the autos and fringes for `StatsOptions::file_format`
+enum Writers<'a> {
+    Dat(autos::AutosFileWriter, fringes::FringesFileWriter),
+    Npy(NpyWriter<'a>),
+    Npz(NpzWriter<'a>),
+}
+
+impl<'a> Writers<'a> {
+    fn new(context: &'a CorrelatorContext, options: &StatsOptions) -> Self {
+        let output_dir = &options.output_dir;
+        match options.file_format {
+            FileFormat::Dat => Writers::Dat(
+                autos::AutosFileWriter::new(
+                    ObsInfo::from_context(context),
+                    output_dir,
+                    options.output_format,
+                ),
+                fringes::FringesFileWriter::new(
+                    ObsInfo::from_context(context),
+                    output_dir,
+                    options.fringes_version,
+                    options.output_format,
+                ),
+            ),
+            FileFormat::Npy => {
+                Writers::Npy(NpyWriter::new(context, output_dir, options.fringes_version))
+            }
+            FileFormat::Npz => {
+                Writers::Npz(NpzWriter::new(context, output_dir, options.fringes_version))
+            }
+        }
+    }
+
+    fn autos(&mut self) -> &mut dyn autos::AutosWriter {
+        match self {
+            Writers::Dat(autos_writer, _) => autos_writer,
+            Writers::Npy(writer) => writer,
+            Writers::Npz(writer) => writer,
+        }
+    }
+
+    fn fringes(&mut self) -> &mut dyn fringes::FringesWriter {
+        match self {
+            Writers::Dat(_, fringes_writer) => fringes_writer,
+            Writers::Npy(writer) => writer,
+            Writers::Npz(writer) => writer,
+        }
+    }
+
+    /// The .npz bundle is only written once everything has been added to it
+    fn finish(self) -> Result<(), MwaxStatsError> {
+        match self {
+            Writers::Npz(writer) => writer.finish(),
+            _ => Ok(()),
+        }
+    }
+}
@@ -5,12 +5,14 @@
 use std::path::Path;
 use std::fs::File;
 use std::io::prelude::*;
-use std::io::SeekFrom;
+use std::io::{BufWriter, SeekFrom};
 use std::str;
 use anyhow::{anyhow, Result};
 use ndarray::Array;
 use log::{debug,info};
 use rayon::prelude::*;
+use crate::errors::MwaxStatsError;
+use crate::numpy::{self, FileFormat};
 
 const PSRDADA_HEADER_LEN:usize = 4096;
 const KEY_SUBOBS_ID: &str = "SUBOBS_ID";
@@ -29,22 +31,38 @@ struct PsrdadaHeader {
     chan: String
 }
 
-/// Reads the packet stats from a subfile and writes a count of lost packets per input (1 tile=2 inputs)
+/// 
+/// The packet stats read from a subfile
+/// 
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PacketStats {
+    /// The subobservation id, from the header
+    pub subobs_id: String,
+    /// The coarse channel, from the header
+    pub chan: String,
+    /// Count of lost packets per input (1 tile=2 inputs)
+    pub packets_lost: Vec<u16>,
+}
+
+impl PacketStats {
+    /// The number of tiles (2 inputs each)
+    pub fn num_tiles(&self) -> usize {
+        self.packets_lost.len() / 2
+    }
+}
+
+/// Reads the packet stats from a subfile and counts the lost packets per input (1 tile=2 inputs)
 ///
 /// # Arguments
 ///
 /// * `subfile_name` - Reference to path to the subfile to read
 /// 
-/// * `output_dir` - Reference to the path to the output dir to write the file (the filename is generated)
-/// 
-/// * `hostname` - Reference to a string containing the hostname (used when generating the output filename)
-/// 
 ///
 /// # Returns
 ///
-/// * Result - Ok on success (and file written), or an error on failure
+/// * Result - Ok containing the populated `PacketStats` on success, or an error on failure
 /// 
-pub(crate) fn process_subfile_packet_map_data(subfile_name: &Path, output_dir: &Path, hostname: &str) -> Result<(), anyhow::Error> {    
+pub fn get_packet_stats(subfile_name: &Path) -> Result<PacketStats, anyhow::Error> {    
     // Open the subfile    
     let mut file = File::open(subfile_name)?;
 
@@ -57,11 +75,44 @@ pub(crate) fn process_subfile_packet_map_data(subfile_name: &Path, output_dir: &
     // Read packet map from file and populate the packet map array
     read_packet_map(&mut file, info.ninputs, info.map_start_index, info.map_length, &mut packets_lost)?;
 
+    Ok(PacketStats { subobs_id: info.subobs_id, chan: info.chan, packets_lost })
+}
+
+/// Reads the packet stats from a subfile and writes a count of lost packets per input (1 tile=2 inputs)
+///
+/// # Arguments
+///
+/// * `subfile_name` - Reference to path to the subfile to read
+/// 
+/// * `output_dir` - Reference to the path to the output dir to write the file (the filename is generated)
+/// 
+/// * `hostname` - Reference to a string containing the hostname (used when generating the output filename)
+/// 
+/// * `file_format` - Write a .dat file or a .npy file (of u16 with a shape of [input])
+/// 
+///
+/// # Returns
+///
+/// * Result - Ok on success (and file written), or the MwaxStatsError (reading the subfile or writing the file) on failure
+/// 
+pub fn process_subfile_packet_map_data(subfile_name: &Path, output_dir: &Path, hostname: &str, file_format: FileFormat) -> Result<(), MwaxStatsError> {    
+    let stats = get_packet_stats(subfile_name).map_err(|e| MwaxStatsError::ReadSubfile { filename: subfile_name.to_path_buf(), reason: e.to_string() })?;
+
     // Determine output filename
-    let output_filename = output_dir.join(format!("packetstats_{}_{}T_ch{}_{}.dat", info.subobs_id, info.ninputs/2, info.chan, hostname));
+    let extension = match file_format {
+        FileFormat::Dat => "dat",
+        FileFormat::Npy => "npy",
+        FileFormat::Npz => return Err(MwaxStatsError::InvalidArgument("Packet stats can not be written to a .npz file".to_string())),
+    };
+    let output_filename = output_dir.join(format!("packetstats_{}_{}T_ch{}_{}.{}", stats.subobs_id, stats.num_tiles(), stats.chan, hostname, extension));
 
     // Write file
-    write_packet_stats(&packets_lost, &output_filename)?;
+    if file_format == FileFormat::Npy {
+        write_packet_stats_npy(&stats.packets_lost, &output_filename)
+    } else {
+        write_packet_stats(&stats.packets_lost, &output_filename)
+    }
+    .map_err(|e| MwaxStatsError::write_file(&output_filename, e))?;
 
     info!("Successfully wrote packet stats to: {}", output_filename.display());
 
@@ -128,17 +179,39 @@ fn read_packet_map(file: &mut File, ninputs: usize, map_start_index: u64, map_le
 ///
 /// * Result - Ok on success, or an error on failure
 ///
-fn write_packet_stats(packets_lost: &[u16], output_filename: &Path) -> Result<(),anyhow::Error>{
+pub(crate) fn write_packet_stats(packets_lost: &[u16], output_filename: &Path) -> std::io::Result<()>{
     // Now write the data file    
     let mut out_file:  File = File::create(output_filename)?;
     
     // Write (after converting the uint16's to 2 bytes (as little endian))
-    let _ = out_file.write_all(&packets_lost.iter().flat_map(|int| int.to_le_bytes()).collect::<Vec<u8>>());
+    out_file.write_all(&packets_lost.iter().flat_map(|int| int.to_le_bytes()).collect::<Vec<u8>>())?;
     out_file.flush()?;
     
     Ok(())
 }
 
+/// Write packet stats to disk as a .npy file
+///
+/// # Arguments
+///
+/// * `packets_lost` - Reference to array or slice of u16's representing packets lost counts (1 element per input)
+/// 
+/// * `output_filename`- filename to write to as a `Path` reference
+/// 
+///
+/// # Returns
+///
+/// * Result - Ok on success, or an error on failure
+///
+fn write_packet_stats_npy(packets_lost: &[u16], output_filename: &Path) -> std::io::Result<()>{
+    let mut out_file = BufWriter::new(File::create(output_filename)?);
+
+    numpy::write_npy(&mut out_file, &Array::from(packets_lost.to_vec()))?;
+    out_file.flush()?;
+
+    Ok(())
+}
+
 /// Read values from the PSRDADA header of the subfile
 ///
 /// # Arguments
@@ -237,6 +310,16 @@ mod tests {
         assert!(read_subfile_header_key(&test_header, "unknown_key").is_err());
     }
 
+    #[test]
+    fn test_process_subfile_packet_map_data_errors() {
+        let dir = tempfile::tempdir().unwrap();
+
+        // No subfile
+        let result = process_subfile_packet_map_data(&dir.path().join("missing.sub"), dir.path(), "mwax01", FileFormat::Dat);
+        assert!(matches!(result, Err(MwaxStatsError::ReadSubfile { .. })));
+        assert_eq!(result.unwrap_err().exit_code(), 10);
+    }
+
     #[test]
     fn test_write_packet_stats() {    
         // Setup
@@ -277,6 +360,19 @@ mod tests {
         assert_eq!(p.subobs_id, "1419789248");
     }
 
+    #[test]
+    fn test_get_packet_stats() {
+        let filename = "test_files/1419789248_1419789248_91_small.sub";
+
+        let stats = get_packet_stats(Path::new(filename)).unwrap();
+
+        assert_eq!(stats.subobs_id, "1419789248");
+        assert_eq!(stats.chan, "91");
+        assert_eq!(stats.num_tiles(), 120);
+        assert_eq!(stats.packets_lost[8], 1);
+        assert_eq!(stats.packets_lost[110], 5000);
+    }
+
     #[test]
     fn test_read_packet_map() {
         let filename = "test_files/1419789248_1419789248_91_small.sub";